@@ -0,0 +1,130 @@
+pub mod errors;
+
+use errors::AmfiError;
+
+use crate::lockdown::service::{ServiceConnection, AMFI_SERVICE};
+use crate::lockdown::LockdownClient;
+use crate::usbmux::message::{AmfiRequest, AmfiResponse};
+
+/// Shows the Developer Mode switch in Settings > Privacy & Security.
+const ACTION_REVEAL: u32 = 0;
+/// Turns developer mode on and restarts the device.
+const ACTION_ARM: u32 = 1;
+/// Accepts the "Turn On Developer Mode?" prompt shown after the restart.
+const ACTION_ENABLE: u32 = 2;
+
+/// A client for `com.apple.amfi.lockdown`, which manages developer mode.
+pub struct AmfiClient {
+    service: ServiceConnection,
+}
+
+impl AmfiClient {
+    /// Starts the AMFI service over `lockdown`, which needs a session.
+    pub async fn connect(lockdown: &mut LockdownClient) -> Result<Self, AmfiError> {
+        let service = lockdown.start_service(AMFI_SERVICE, None).await?;
+        Ok(AmfiClient::new(service))
+    }
+
+    /// Creates a client over a connection to the AMFI service.
+    pub fn new(service: ServiceConnection) -> Self {
+        AmfiClient { service }
+    }
+
+    async fn request(&mut self, action: u32, name: &'static str) -> Result<(), AmfiError> {
+        self.service.send_plist(&AmfiRequest { action }).await?;
+        let response: AmfiResponse = self.service.read_plist().await?;
+        match response.error {
+            Some(error) if error.contains("passcode") => Err(AmfiError::PasscodeSet),
+            Some(error) => Err(AmfiError::Error(error)),
+            None if response.success == Some(true) => Ok(()),
+            None => Err(AmfiError::Failed(name)),
+        }
+    }
+
+    /// Shows the Developer Mode switch in Settings.
+    pub async fn reveal_developer_mode(&mut self) -> Result<(), AmfiError> {
+        self.request(ACTION_REVEAL, "revealing developer mode")
+            .await
+    }
+
+    /// Turns developer mode on. The device restarts right away and asks for
+    /// confirmation once it is back, see
+    /// [`AmfiClient::enable_developer_mode_post_restart`].
+    ///
+    /// # Errors
+    ///
+    /// Returns [`AmfiError::PasscodeSet`] if the device has a passcode, in
+    /// which case developer mode can only be turned on in Settings.
+    pub async fn arm_developer_mode(&mut self) -> Result<(), AmfiError> {
+        self.request(ACTION_ARM, "arming developer mode").await
+    }
+
+    /// Confirms the developer mode prompt shown after the restart.
+    pub async fn enable_developer_mode_post_restart(&mut self) -> Result<(), AmfiError> {
+        self.request(ACTION_ENABLE, "enabling developer mode").await
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use plist::{Dictionary, Value};
+    use tokio::io::{AsyncReadExt, AsyncWriteExt, DuplexStream};
+
+    use super::{errors::AmfiError, AmfiClient};
+    use crate::lockdown::service::ServiceConnection;
+    use crate::usbmux::UsbMuxClient;
+
+    async fn reply(server: &mut DuplexStream, response: Dictionary) -> u64 {
+        let length = server.read_u32().await.unwrap();
+        let mut payload = vec![0u8; length as usize];
+        server.read_exact(&mut payload).await.unwrap();
+        let request: Dictionary = plist::from_bytes(&payload).unwrap();
+
+        let mut bytes = Vec::new();
+        plist::to_writer_xml(&mut bytes, &response).unwrap();
+        server.write_u32(bytes.len() as u32).await.unwrap();
+        server.write_all(&bytes).await.unwrap();
+
+        request
+            .get("action")
+            .and_then(|v| v.as_unsigned_integer())
+            .unwrap()
+    }
+
+    #[tokio::test]
+    async fn test_amfi_replies() {
+        let (client_end, mut server) = tokio::io::duplex(4096);
+        let mut amfi = AmfiClient::new(ServiceConnection {
+            usbmux: UsbMuxClient::from_transport(Box::new(client_end)),
+            port: 0,
+            ssl: false,
+        });
+
+        let device = tokio::spawn(async move {
+            let mut success = Dictionary::new();
+            success.insert("success".to_string(), Value::Boolean(true));
+            let mut passcode = Dictionary::new();
+            passcode.insert(
+                "Error".to_string(),
+                Value::from("Device has a passcode set"),
+            );
+            let mut actions = Vec::new();
+            for response in [success.clone(), passcode, Dictionary::new(), success] {
+                actions.push(reply(&mut server, response).await);
+            }
+            actions
+        });
+
+        amfi.reveal_developer_mode().await.unwrap();
+        assert!(matches!(
+            amfi.arm_developer_mode().await,
+            Err(AmfiError::PasscodeSet)
+        ));
+        assert!(matches!(
+            amfi.arm_developer_mode().await,
+            Err(AmfiError::Failed(_))
+        ));
+        amfi.enable_developer_mode_post_restart().await.unwrap();
+        assert_eq!(device.await.unwrap(), vec![0, 1, 1, 2]);
+    }
+}
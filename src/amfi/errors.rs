@@ -0,0 +1,13 @@
+use crate::lockdown::errors::LockdownError;
+
+#[derive(Debug, thiserror::Error)]
+pub enum AmfiError {
+    #[error("Lockdown error: {0}")]
+    LockdownError(#[from] LockdownError),
+    #[error("Developer mode cannot be enabled from the host while a passcode is set, enable it in Settings")]
+    PasscodeSet,
+    #[error("AMFI did not confirm {0}")]
+    Failed(&'static str),
+    #[error("AMFI error: {0}")]
+    Error(String),
+}
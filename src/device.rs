@@ -1,35 +1,139 @@
+pub mod doctor;
 pub mod error;
 
-use std::path::PathBuf;
+use std::{
+    net::{Ipv6Addr, SocketAddr},
+    path::{Path, PathBuf},
+    sync::{Arc, RwLock},
+    time::Duration,
+};
 
 use error::DeviceError;
+use log::error;
 use tokio::sync::mpsc::UnboundedReceiver;
+use tokio_stream::{Stream, StreamExt};
 
+use crate::amfi::AmfiClient;
 use crate::dtservice::DtServiceHandler;
-use crate::tunnel::Tunnel;
+use crate::lockdown::errors::LockdownError;
+use crate::lockdown::heartbeat::HeartbeatClient;
+use crate::lockdown::service::CORE_DEVICE_PROXY_SERVICE;
+use crate::transport::BoxedTransport;
+use crate::tunnel::userspace::UserspaceStack;
+use crate::tunnel::{self, Dialer, Tunnel};
+use crate::usbmux::errors::{
+    MessageOperationError, PairRecordError, SslError, UsbmuxOperationError,
+};
 use crate::usbmux::UsbMuxClient;
 use crate::xpc::XpcHandler;
 
+pub use crate::forward::{Forward, ForwardCounters, ForwardTarget};
+pub use crate::lockdown::device_info::DeviceInfo as LockdownDeviceInfo;
+pub use crate::lockdown::service::{ServiceConnection, SslMode};
+pub use crate::lockdown::LockdownClient;
+pub use crate::tunnel::TunnelMode;
+pub use crate::usbmux::device_info::{ConnectionType, DeviceInfo};
+pub use crate::usbmux::listen::UsbmuxEvent;
+pub use crate::usbmux::message::PlistFormat;
+pub use crate::usbmux::pair_record::{PairRecord, PairRecordStore, Passphrase};
+pub use crate::usbmux::DeviceConnection;
+pub use doctor::{Check, CheckStatus};
+
+/// How long `pair` waits for the user to answer the trust dialog.
+const PAIRING_TIMEOUT: Duration = Duration::from_secs(120);
+/// How long `enable_developer_mode` waits for the device to restart and
+/// accept a lockdown session again.
+const RESTART_TIMEOUT: Duration = Duration::from_secs(300);
+
 pub struct Device {
     tunnel: Option<Tunnel>,
+    stack: Option<UserspaceStack>,
+    /// Local ports relayed to the device.
+    forwards: Vec<Forward>,
     device_addr: Option<String>,
     device_port: Option<u16>,
     connection: Option<DtServiceHandler>,
+    /// Answers the device's heartbeats while connected over Wi-Fi, until the
+    /// tunnel stops or is replaced.
+    heartbeat: Option<tokio::task::JoinHandle<()>>,
+    /// Stops the tunnel when usbmuxd reports the device as detached.
+    detach_watcher: Option<tokio::task::JoinHandle<()>>,
+    /// Where pair records are read from. `None` asks usbmuxd.
+    pub pair_record_store: Option<PairRecordStore>,
+    /// Whether TLS connections require the device certificate from the pair
+    /// record. See [`UsbMuxClient::pin_device_certificate`].
+    pub pin_device_certificate: bool,
+    /// Only use devices attached this way. `None` prefers USB and falls back
+    /// to Wi-Fi.
+    pub connection_type: Option<ConnectionType>,
+    /// Whether devices are reached through usbmuxd or directly by address.
+    pub device_connection: DeviceConnection,
+    /// Whether the tunnel gets a host network interface or an in-process
+    /// TCP/IP stack.
+    pub tunnel_mode: TunnelMode,
+    /// Format of the plists sent to usbmuxd, lockdownd and its services.
+    /// Replies are accepted in either format.
+    pub plist_format: PlistFormat,
 }
 
 impl Device {
     pub fn new() -> Self {
         Device {
             tunnel: None,
+            stack: None,
+            forwards: Vec::new(),
             device_addr: None,
             device_port: None,
             connection: None,
+            heartbeat: None,
+            detach_watcher: None,
+            pair_record_store: None,
+            pin_device_certificate: true,
+            connection_type: None,
+            device_connection: DeviceConnection::Usbmux,
+            tunnel_mode: TunnelMode::Interface,
+            plist_format: PlistFormat::Xml,
         }
     }
 
+    pub async fn list_devices(&self) -> Result<Vec<DeviceInfo>, DeviceError> {
+        let mut usbmux_client = UsbMuxClient::new().await?;
+        usbmux_client.plist_format = self.plist_format;
+        Ok(usbmux_client.list_devices().await?)
+    }
+
+    /// Streams attach/detach/pair notifications from usbmuxd.
+    pub async fn events(
+        &self,
+    ) -> Result<impl Stream<Item = Result<UsbmuxEvent, UsbmuxOperationError>>, DeviceError> {
+        Ok(UsbMuxClient::listen().await?)
+    }
+
+    /// Opens a usbmuxd client, or a direct one, using this device's pair
+    /// record store, certificate pinning, connection type and plist format
+    /// settings.
+    async fn usbmux_client(&self) -> Result<UsbMuxClient, DeviceError> {
+        let mut usbmux_client = match self.device_connection {
+            DeviceConnection::Usbmux => UsbMuxClient::new().await?,
+            DeviceConnection::Direct(address) => UsbMuxClient::direct(address),
+        };
+        usbmux_client.connection_type = self.connection_type.clone();
+        usbmux_client.pair_record_store = self.pair_record_store.clone();
+        usbmux_client.pin_device_certificate = self.pin_device_certificate;
+        usbmux_client.plist_format = self.plist_format;
+        Ok(usbmux_client)
+    }
+
+    /// Connects to the device with the given UDID, or the first attached
+    /// device when `udid` is `None`, replacing the current tunnel and
+    /// stopping the forwards through it. Over Wi-Fi, the device's heartbeats
+    /// are answered until the tunnel stops. In [`TunnelMode::Interface`] the
+    /// tunnel interface is a Wintun adapter, loaded from `wintun_path`, on
+    /// Windows and a `/dev/net/tun` interface on Linux.
     pub async fn connect(
         &mut self,
         wintun_path: PathBuf,
+        udid: Option<&str>,
     ) -> Result<
         (
             tokio::task::JoinHandle<()>,
@@ -38,42 +142,206 @@ impl Device {
         ),
         DeviceError,
     > {
-        let mut usbmux_client = UsbMuxClient::new().await?;
-        usbmux_client.get_device_pair_record().await?;
-        usbmux_client.connect_to_lockdown().await?;
-        usbmux_client.start_lockdown_session().await?;
-        usbmux_client.conncet_to_cdp().await?;
-        let (mut addr, mut mtu, mut server_addr, mut server_port) =
-            usbmux_client.try_cdp_handshake().await?;
+        let (mut lockdown, device_info) = self.lockdown_session(udid).await?;
+        if !lockdown.developer_mode_status().await? {
+            return Err(DeviceError::Error("Developer mode disabled"));
+        }
+        let heartbeat = match device_info.connection_type {
+            ConnectionType::Network => Some(HeartbeatClient::connect(&mut lockdown).await?),
+            _ => None,
+        };
+        let mut service = lockdown
+            .start_service(CORE_DEVICE_PROXY_SERVICE, None)
+            .await?;
+        let (addr, mtu, server_addr, server_port) = service.usbmux.try_cdp_handshake().await?;
 
+        let address = addr
+            .parse()
+            .map_err(|_| DeviceError::Error("Invalid tunnel address"))?;
+        let (reader, writer) = tokio::io::split(service.into_transport()?);
+
+        self.stop_tunnel();
+        let (sock_read_handle, tun_read_handle, writer_handle) = match self.tunnel_mode {
+            TunnelMode::Interface => {
+                let backend = tunnel::create_backend(&wintun_path)?;
+                let tunnel = self.tunnel.insert(Tunnel::new(address, mtu, backend)?);
+                tunnel.on(reader, writer).await
+            }
+            TunnelMode::Userspace => {
+                let stack = self.stack.insert(UserspaceStack::new(address, mtu));
+                stack.on(reader, writer).await
+            }
+        };
         self.device_addr = Some(server_addr);
         self.device_port = Some(server_port);
 
-        self.tunnel = Some(Tunnel::new(addr, mtu, wintun_path));
-
-        let (mut reader, mut writer) = tokio::io::split(
-            usbmux_client
-                .ssl_sock
-                .take()
-                .ok_or_else(|| DeviceError::Error("No ssl sock in usbmux client"))?,
-        );
+        if let Some(heartbeat) = heartbeat {
+            let termination_token = self.termination_token()?;
+            self.heartbeat = Some(tokio::spawn(async move {
+                tokio::select! {
+                    result = heartbeat.run() => {
+                        if let Err(e) = result {
+                            error!("Heartbeat stopped: {}", e);
+                        }
+                    }
+                    _ = tunnel::terminated(&termination_token) => {}
+                }
+            }));
+        }
 
-        let (sock_read_handle, tun_read_handle, writer_handle) =
-            self.tunnel.as_mut().unwrap().on(reader, writer).await;
+        if self.device_connection == DeviceConnection::Usbmux {
+            self.spawn_detach_watcher(device_info.device_id).await?;
+        }
 
         Ok((sock_read_handle, tun_read_handle, writer_handle))
     }
 
+    /// Stops the current tunnel, if any, its heartbeat, its detach watcher
+    /// and the forwards through it. Forwards over usbmuxd keep running.
+    fn stop_tunnel(&mut self) {
+        self.device_addr = None;
+        self.device_port = None;
+        self.forwards
+            .retain(|forward| matches!(forward.target, ForwardTarget::Usbmux { .. }));
+        if let Some(heartbeat) = self.heartbeat.take() {
+            heartbeat.abort();
+        }
+        if let Some(detach_watcher) = self.detach_watcher.take() {
+            detach_watcher.abort();
+        }
+        if let Some(mut tunnel) = self.tunnel.take() {
+            tunnel.terminate();
+        }
+        if let Some(mut stack) = self.stack.take() {
+            stack.terminate();
+        }
+    }
+
+    /// Returns the token that stops the current tunnel.
+    fn termination_token(&self) -> Result<Arc<RwLock<bool>>, DeviceError> {
+        match (&self.tunnel, &self.stack) {
+            (Some(tunnel), _) => Ok(tunnel.termination_token()),
+            (None, Some(stack)) => Ok(stack.termination_token()),
+            (None, None) => Err(DeviceError::Error("No tunnel")),
+        }
+    }
+
+    /// Stops the tunnel as soon as usbmuxd reports the device as detached.
+    async fn spawn_detach_watcher(&mut self, device_id: u16) -> Result<(), DeviceError> {
+        let termination_token = self.termination_token()?;
+        let events = UsbMuxClient::listen().await?;
+
+        self.detach_watcher = Some(tokio::spawn(async move {
+            tokio::pin!(events);
+            while let Some(Ok(event)) = events.next().await {
+                if let UsbmuxEvent::Detached { device_id: id } = event {
+                    if id == device_id {
+                        *termination_token.write().unwrap() = true;
+                        break;
+                    }
+                }
+            }
+        }));
+
+        Ok(())
+    }
+
+    /// Returns how to reach the device through the tunnel, and its address
+    /// there.
+    fn dialer(&self) -> Result<(Dialer, Ipv6Addr), DeviceError> {
+        let address = self
+            .device_addr
+            .as_ref()
+            .ok_or(DeviceError::Error("Missing device addr"))?
+            .parse()
+            .map_err(|_| DeviceError::Error("Invalid device addr"))?;
+        match (&self.tunnel, &self.stack) {
+            (Some(_), _) => Ok((Dialer::Interface, address)),
+            (None, Some(stack)) => Ok((Dialer::Userspace(stack.connector()), address)),
+            (None, None) => Err(DeviceError::Error("No tunnel")),
+        }
+    }
+
+    /// Opens a TCP connection to `port` on the device through the tunnel.
+    async fn connect_through_tunnel(&self, port: u16) -> Result<BoxedTransport, DeviceError> {
+        let (dialer, address) = self.dialer()?;
+        Ok(dialer.connect(address, port).await?)
+    }
+
+    /// Relays connections accepted on `local_addr` to `device_port` on the
+    /// connected device, through the tunnel, until stopped with
+    /// [`stop_forward`](Self::stop_forward).
+    pub async fn forward(
+        &mut self,
+        local_addr: SocketAddr,
+        device_port: u16,
+    ) -> Result<&Forward, DeviceError> {
+        let (dialer, address) = self.dialer()?;
+        let target = ForwardTarget::Tunnel {
+            dialer,
+            address,
+            port: device_port,
+        };
+        self.start_forward(local_addr, target).await
+    }
+
+    /// Relays connections accepted on `local_addr` to `device_port` on the
+    /// device with the given UDID, or the first attached device, opening a
+    /// new usbmuxd connection for each. Needs no tunnel.
+    pub async fn usbmux_forward(
+        &mut self,
+        local_addr: SocketAddr,
+        device_port: u16,
+        udid: Option<&str>,
+    ) -> Result<&Forward, DeviceError> {
+        let device_info = self.usbmux_client().await?.select_device(udid).await?;
+        let target = ForwardTarget::Usbmux {
+            device_connection: self.device_connection.clone(),
+            connection_type: self.connection_type.clone(),
+            udid: device_info.serial_number,
+            port: device_port,
+        };
+        self.start_forward(local_addr, target).await
+    }
+
+    /// Runs a SOCKS5 proxy on `local_addr` that connects clients to any
+    /// address in the tunnel's /64, through the tunnel, until stopped with
+    /// [`stop_forward`](Self::stop_forward).
+    pub async fn socks_proxy(&mut self, local_addr: SocketAddr) -> Result<&Forward, DeviceError> {
+        let (dialer, network) = self.dialer()?;
+        self.start_forward(local_addr, ForwardTarget::Socks { dialer, network })
+            .await
+    }
+
+    async fn start_forward(
+        &mut self,
+        local_addr: SocketAddr,
+        target: ForwardTarget,
+    ) -> Result<&Forward, DeviceError> {
+        let forward = Forward::start(local_addr, target).await?;
+        self.forwards.push(forward);
+        Ok(self.forwards.last().unwrap())
+    }
+
+    /// Forwards started with [`forward`](Self::forward).
+    pub fn forwards(&self) -> &[Forward] {
+        &self.forwards
+    }
+
+    /// Stops the forward listening on `local_port`. Returns whether there
+    /// was one.
+    pub fn stop_forward(&mut self, local_port: u16) -> bool {
+        let count = self.forwards.len();
+        self.forwards
+            .retain(|forward| forward.local_addr.port() != local_port);
+        self.forwards.len() != count
+    }
+
     async fn get_dt_service_port(&self) -> Result<u16, DeviceError> {
-        let (addr, port) = (
-            self.device_addr
-                .as_ref()
-                .ok_or(DeviceError::Error("Missing device addr"))?,
-            self.device_port
-                .as_ref()
-                .ok_or(DeviceError::Error("Missing device port"))?,
-        );
-        let mut xpc_handler = XpcHandler::new(&addr, &port).await;
+        let port = self
+            .device_port
+            .ok_or(DeviceError::Error("Missing device port"))?;
+        let mut xpc_handler = XpcHandler::from_transport(self.connect_through_tunnel(port).await?);
         xpc_handler.do_handshake().await?;
 
         let dt_port = xpc_handler
@@ -83,12 +351,9 @@ impl Device {
     }
 
     pub async fn simulate_location(&mut self, lat: f64, lng: f64) -> Result<(), DeviceError> {
-        let dt_addr = self
-            .device_addr
-            .as_ref()
-            .ok_or(DeviceError::Error("No addr"))?;
         let dt_port = self.get_dt_service_port().await?;
-        let mut dt_service_handler = DtServiceHandler::new(dt_addr, &dt_port).await?;
+        let mut dt_service_handler =
+            DtServiceHandler::from_transport(self.connect_through_tunnel(dt_port).await?);
 
         dt_service_handler.start_channel(String::from("")).await?;
 
@@ -99,23 +364,444 @@ impl Device {
         Ok(())
     }
 
-    pub async fn reveal_developer_mode(&mut self) -> Result<(), DeviceError> {
-        let mut usbmux_client = UsbMuxClient::new().await?;
+    /// Opens a lockdown session with the device with the given UDID, or the
+    /// first attached device, using its pair record.
+    async fn lockdown_session(
+        &self,
+        udid: Option<&str>,
+    ) -> Result<(LockdownClient, DeviceInfo), DeviceError> {
+        let mut usbmux_client = self.usbmux_client().await?;
+        let device_info = usbmux_client.select_device(udid).await?;
         usbmux_client.get_device_pair_record().await?;
-        usbmux_client.connect_to_lockdown().await?;
-        usbmux_client.start_lockdown_session().await?;
-        usbmux_client.connect_to_amfi().await?;
+        let mut lockdown = LockdownClient::connect(usbmux_client).await?;
+        lockdown.start_session().await?;
+        Ok((lockdown, device_info))
+    }
+
+    /// Opens a lockdown session with the device with the given UDID, or the
+    /// first attached device, for requests `Device` has no method for.
+    pub async fn lockdown(&self, udid: Option<&str>) -> Result<LockdownClient, DeviceError> {
+        let (lockdown, _) = self.lockdown_session(udid).await?;
+        Ok(lockdown)
+    }
+
+    /// Pairs with the device with the given UDID, or the first attached
+    /// device. The user has to tap "Trust" on the device.
+    pub async fn pair(&mut self, udid: Option<&str>) -> Result<PairRecord, DeviceError> {
+        let mut usbmux_client = self.usbmux_client().await?;
+        let device_info = usbmux_client.select_device(udid).await?;
+        let system_buid = usbmux_client.read_buid().await?;
+        let mut lockdown = LockdownClient::connect(usbmux_client).await?;
+        let record = lockdown.pair(system_buid, PAIRING_TIMEOUT).await?;
+
+        let mut usbmux_client = self.usbmux_client().await?;
+        usbmux_client.use_device(&device_info);
+        usbmux_client.save_device_pair_record(&record).await?;
+        Ok(record)
+    }
+
+    /// Reads the name, model and iOS version of the device with the given
+    /// UDID, or the first attached device. Works without a pair record too,
+    /// though lockdownd then withholds some values; other pair record errors
+    /// are returned.
+    pub async fn device_info(&self, udid: Option<&str>) -> Result<LockdownDeviceInfo, DeviceError> {
+        let mut usbmux_client = self.usbmux_client().await?;
+        usbmux_client.select_device(udid).await?;
+        let paired = match usbmux_client.get_device_pair_record().await {
+            Ok(()) => true,
+            Err(UsbmuxOperationError::PairRecordError(PairRecordError::NotFound(_))) => false,
+            Err(error) => return Err(error.into()),
+        };
+        let mut lockdown = LockdownClient::connect(usbmux_client).await?;
+        if paired {
+            lockdown.start_session().await?;
+        }
+        Ok(lockdown.device_info().await?)
+    }
+
+    /// Writes the pair record of the device with the given UDID, or the first
+    /// attached device, to `path`, encrypted with `passphrase` if given, so
+    /// another host can use it with `import_pair_record`.
+    pub async fn export_pair_record(
+        &self,
+        udid: Option<&str>,
+        path: &Path,
+        passphrase: Option<&Passphrase>,
+    ) -> Result<PairRecord, DeviceError> {
+        let mut usbmux_client = self.usbmux_client().await?;
+        usbmux_client.select_device(udid).await?;
+        let record = usbmux_client.read_device_pair_record().await?;
+        record.write_file(path, passphrase).await?;
+        Ok(record)
+    }
+
+    /// Reads a pair record exported from another host, checks that the
+    /// device with the given UDID, or the first attached device, trusts it,
+    /// and stores it as that device's pair record.
+    pub async fn import_pair_record(
+        &self,
+        udid: Option<&str>,
+        path: &Path,
+        passphrase: Option<&Passphrase>,
+    ) -> Result<PairRecord, DeviceError> {
+        let record = PairRecord::read_file(path, passphrase).await?;
+
+        let mut usbmux_client = self.usbmux_client().await?;
+        let device_info = usbmux_client.select_device(udid).await?;
+        let mut lockdown = LockdownClient::connect(usbmux_client).await?;
+        lockdown.validate_pair(&record).await?;
+
+        let mut usbmux_client = self.usbmux_client().await?;
+        usbmux_client.use_device(&device_info);
+        usbmux_client.save_device_pair_record(&record).await?;
+        Ok(record)
+    }
+
+    /// Checks that the device with the given UDID, or the first attached
+    /// device, still trusts its pair record.
+    pub async fn validate_pair(&self, udid: Option<&str>) -> Result<(), DeviceError> {
+        let mut usbmux_client = self.usbmux_client().await?;
+        usbmux_client.select_device(udid).await?;
+        let record = usbmux_client.read_device_pair_record().await?;
+        let mut lockdown = LockdownClient::connect(usbmux_client).await?;
+        Ok(lockdown.validate_pair(&record).await?)
+    }
+
+    /// Revokes this host's trust on the device with the given UDID, or the
+    /// first attached device, and deletes its pair record.
+    pub async fn unpair(&self, udid: Option<&str>) -> Result<(), DeviceError> {
+        let mut usbmux_client = self.usbmux_client().await?;
+        let device_info = usbmux_client.select_device(udid).await?;
+        let record = usbmux_client.read_device_pair_record().await?;
+        let mut lockdown = LockdownClient::connect(usbmux_client).await?;
+        lockdown.unpair(&record).await?;
+
+        let mut usbmux_client = self.usbmux_client().await?;
+        usbmux_client.use_device(&device_info);
+        usbmux_client.delete_device_pair_record().await?;
+        Ok(())
+    }
+
+    /// Allows or forbids lockdown connections over Wi-Fi on the device with
+    /// the given UDID, or the first attached device. Once allowed, usbmuxd
+    /// lists the device as a network device while it shares a network with
+    /// this host.
+    pub async fn set_wifi_connections(
+        &self,
+        udid: Option<&str>,
+        enabled: bool,
+    ) -> Result<(), DeviceError> {
+        let (mut lockdown, _) = self.lockdown_session(udid).await?;
+        Ok(lockdown.set_wifi_connections(enabled).await?)
+    }
+
+    pub async fn reveal_developer_mode(&mut self, udid: Option<&str>) -> Result<(), DeviceError> {
+        let (mut lockdown, _) = self.lockdown_session(udid).await?;
+        let mut amfi = AmfiClient::connect(&mut lockdown).await?;
+        amfi.reveal_developer_mode().await?;
+        Ok(())
+    }
+
+    /// Turns developer mode on for the device with the given UDID, or the
+    /// first attached device, without touching the device: AMFI arms it and
+    /// restarts the device, and once it is back the confirmation prompt is
+    /// accepted from the host. Does nothing if developer mode is already on.
+    ///
+    /// Only works on devices without a passcode, and needs usbmuxd to see
+    /// the device restart.
+    pub async fn enable_developer_mode(&mut self, udid: Option<&str>) -> Result<(), DeviceError> {
+        if let DeviceConnection::Direct(_) = self.device_connection {
+            return Err(DeviceError::Error(
+                "Enabling developer mode needs usbmuxd, not a direct connection",
+            ));
+        }
+        let (mut lockdown, device_info) = self.lockdown_session(udid).await?;
+        if lockdown.developer_mode_status().await? {
+            return Ok(());
+        }
+
+        let events = UsbMuxClient::listen().await?;
+        AmfiClient::connect(&mut lockdown)
+            .await?
+            .arm_developer_mode()
+            .await?;
+        drop(lockdown);
+
+        let udid = device_info.serial_number.clone();
+        let mut last_error = None;
+        let restarted = tokio::time::timeout(RESTART_TIMEOUT, async {
+            wait_for_restart(events, &device_info).await?;
+            // lockdownd comes up a while after usbmuxd sees the device.
+            loop {
+                match self.lockdown_session(Some(&udid)).await {
+                    Ok((lockdown, _)) => return Ok::<_, DeviceError>(lockdown),
+                    Err(error) if is_restarting(&error) => {
+                        last_error = Some(error);
+                        tokio::time::sleep(Duration::from_secs(2)).await
+                    }
+                    Err(error) => return Err(error),
+                }
+            }
+        })
+        .await;
+        let mut lockdown = match restarted {
+            Ok(lockdown) => lockdown?,
+            Err(_) => {
+                return Err(last_error.unwrap_or(DeviceError::Error(
+                    "Timed out waiting for the device to restart",
+                )))
+            }
+        };
+
+        AmfiClient::connect(&mut lockdown)
+            .await?
+            .enable_developer_mode_post_restart()
+            .await?;
+        if !lockdown.developer_mode_status().await? {
+            return Err(DeviceError::Error("Developer mode is still disabled"));
+        }
         Ok(())
     }
 }
 
+/// Whether `error` is expected while a device restarts: it is not attached
+/// yet, or lockdownd is not answering yet.
+fn is_restarting(error: &DeviceError) -> bool {
+    let usbmux_error = match error {
+        DeviceError::UsbMuxOperationError(error) => error,
+        DeviceError::LockdownError(LockdownError::UsbmuxOperationError(error)) => error,
+        DeviceError::LockdownError(LockdownError::MessageOperationError(error)) => {
+            return matches!(
+                error,
+                MessageOperationError::Io(_) | MessageOperationError::MissingStream
+            )
+        }
+        _ => return false,
+    };
+    matches!(
+        usbmux_error,
+        UsbmuxOperationError::Io(_)
+            | UsbmuxOperationError::UsbmuxSockError(_)
+            | UsbmuxOperationError::ConnectionRefused
+            | UsbmuxOperationError::BadDevice
+            | UsbmuxOperationError::NoDevices
+            | UsbmuxOperationError::DeviceNotFound(_)
+            | UsbmuxOperationError::MessageOperationError(
+                MessageOperationError::Io(_) | MessageOperationError::MissingStream
+            )
+            | UsbmuxOperationError::SslError(SslError::Io(_))
+    )
+}
+
+/// Waits until `device` detaches and the same device attaches again.
+async fn wait_for_restart(
+    events: impl Stream<Item = Result<UsbmuxEvent, UsbmuxOperationError>>,
+    device: &DeviceInfo,
+) -> Result<DeviceInfo, DeviceError> {
+    tokio::pin!(events);
+    let mut detached = false;
+    while let Some(event) = events.next().await {
+        match event? {
+            UsbmuxEvent::Detached { device_id } if device_id == device.device_id => detached = true,
+            UsbmuxEvent::Attached(info)
+                if detached
+                    && info.serial_number == device.serial_number
+                    && info.connection_type == device.connection_type =>
+            {
+                return Ok(info)
+            }
+            _ => {}
+        }
+    }
+    Err(DeviceError::Error("usbmuxd stopped sending events"))
+}
+
 #[cfg(test)]
 mod tests {
     use super::*;
+    use plist::{Data, Dictionary, Value};
     use tokio;
+    use tokio::io::{AsyncReadExt, AsyncWriteExt};
+    use tokio::net::{TcpListener, TcpStream};
+
+    use crate::lockdown::LOCKDOWN_PORT;
 
     #[tokio::test]
     async fn test_device() {
         let mut device = Device::new();
     }
+
+    fn device_info(device_id: u16, serial_number: &str) -> DeviceInfo {
+        DeviceInfo {
+            device_id,
+            serial_number: serial_number.to_string(),
+            connection_type: ConnectionType::Usb,
+            product_id: None,
+            location_id: None,
+            connection_speed: None,
+        }
+    }
+
+    #[tokio::test]
+    async fn test_wait_for_restart() {
+        let phone = device_info(3, "00008030-001A");
+        let events = tokio_stream::iter(vec![
+            Ok(UsbmuxEvent::Attached(device_info(3, "00008030-001A"))),
+            Ok(UsbmuxEvent::Detached { device_id: 4 }),
+            Ok(UsbmuxEvent::Attached(device_info(5, "00008101-0002"))),
+            Ok(UsbmuxEvent::Detached { device_id: 3 }),
+            Ok(UsbmuxEvent::Attached(device_info(6, "00008030-001A"))),
+        ]);
+        let info = wait_for_restart(events, &phone).await.unwrap();
+        assert_eq!(info.device_id, 6);
+
+        let events = tokio_stream::iter(vec![Ok(UsbmuxEvent::Detached { device_id: 3 })]);
+        assert!(wait_for_restart(events, &phone).await.is_err());
+    }
+
+    async fn read_request(sock: &mut TcpStream) -> Dictionary {
+        let length = sock.read_u32().await.unwrap();
+        let mut payload = vec![0u8; length as usize];
+        sock.read_exact(&mut payload).await.unwrap();
+        plist::from_bytes(&payload).unwrap()
+    }
+
+    async fn write_response(sock: &mut TcpStream, response: &[(&str, Value)]) {
+        let response = Dictionary::from_iter(
+            response
+                .iter()
+                .map(|(key, value)| (key.to_string(), value.clone())),
+        );
+        let mut bytes = Vec::new();
+        plist::to_writer_xml(&mut bytes, &response).unwrap();
+        sock.write_u32(bytes.len() as u32).await.unwrap();
+        sock.write_all(&bytes).await.unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_lockdown() {
+        let udid = "00008030-001A";
+        let lockdownd = TcpListener::bind(("127.0.0.1", LOCKDOWN_PORT))
+            .await
+            .unwrap();
+        let dir = std::env::temp_dir().join(format!("device-lockdown-{}", std::process::id()));
+        let store = PairRecordStore::new(&dir);
+        let record = PairRecord {
+            host_id: "2a6e5d36-5b2b-4e3c-9d8b-1f2e3d4c5b6a".to_string(),
+            system_buid: "30F0A1B2-C3D4-E5F6-0718-293A4B5C6D7E".to_string(),
+            host_certificate: Data::new(b"host cert".to_vec()),
+            host_private_key: b"host key".to_vec().into(),
+            device_certificate: None,
+            root_certificate: None,
+            root_private_key: None,
+            escrow_bag: None,
+            wifi_mac_address: None,
+        };
+        store.save(udid, &record).await.unwrap();
+
+        let lockdownd = tokio::spawn(async move {
+            let (mut sock, _) = lockdownd.accept().await.unwrap();
+            let responses = [
+                vec![
+                    ("Request", Value::from("StartSession")),
+                    ("SessionID", Value::from("5A1B")),
+                    ("EnableSessionSSL", Value::Boolean(false)),
+                ],
+                vec![("Type", Value::from("com.apple.mobile.lockdown"))],
+                vec![("Request", Value::from("RemoveValue"))],
+                vec![("Request", Value::from("StopSession"))],
+            ];
+            let mut requests = Vec::new();
+            for response in responses {
+                requests.push(read_request(&mut sock).await);
+                write_response(&mut sock, &response).await;
+            }
+            requests
+        });
+
+        let mut device = Device::new();
+        device.device_connection = DeviceConnection::Direct("127.0.0.1".parse().unwrap());
+        device.pair_record_store = Some(store.clone());
+        let mut lockdown = device.lockdown(Some(udid)).await.unwrap();
+        assert_eq!(lockdown.session_id.as_deref(), Some("5A1B"));
+        assert_eq!(
+            lockdown.query_type().await.unwrap(),
+            "com.apple.mobile.lockdown"
+        );
+        lockdown
+            .remove_value(
+                Some("com.apple.mobile.wireless_lockdown"),
+                "EnableWifiConnections",
+            )
+            .await
+            .unwrap();
+        lockdown.stop_session().await.unwrap();
+        assert!(matches!(
+            lockdown.stop_session().await,
+            Err(LockdownError::SessionInactive)
+        ));
+
+        let requests = lockdownd.await.unwrap();
+        let field = |request: &Dictionary, key| {
+            request
+                .get(key)
+                .and_then(|v| v.as_string())
+                .map(str::to_string)
+        };
+        assert_eq!(
+            field(&requests[0], "HostID").as_deref(),
+            Some("2A6E5D36-5B2B-4E3C-9D8B-1F2E3D4C5B6A")
+        );
+        assert_eq!(field(&requests[1], "Request").as_deref(), Some("QueryType"));
+        assert_eq!(
+            field(&requests[2], "Request").as_deref(),
+            Some("RemoveValue")
+        );
+        assert_eq!(
+            field(&requests[2], "Key").as_deref(),
+            Some("EnableWifiConnections")
+        );
+        assert_eq!(field(&requests[3], "SessionID").as_deref(), Some("5A1B"));
+        tokio::fs::remove_dir_all(&dir).await.unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_plist_format() {
+        let mut device = Device::new();
+        device.device_connection = DeviceConnection::Direct("127.0.0.1".parse().unwrap());
+        device.plist_format = PlistFormat::Binary;
+
+        let mut client = device.usbmux_client().await.unwrap();
+        assert_eq!(client.plist_format, PlistFormat::Binary);
+        let (client_end, mut lockdownd) = tokio::io::duplex(4096);
+        client.sock = Some(Box::new(client_end));
+        client
+            .send_lockdown_message(&crate::usbmux::message::LockdownRequest::QueryType)
+            .await
+            .unwrap();
+        let length = lockdownd.read_u32().await.unwrap();
+        let mut payload = vec![0u8; length as usize];
+        lockdownd.read_exact(&mut payload).await.unwrap();
+        assert!(payload.starts_with(b"bplist00"));
+    }
+
+    #[test]
+    fn test_is_restarting() {
+        assert!(is_restarting(&DeviceError::UsbMuxOperationError(
+            UsbmuxOperationError::ConnectionRefused
+        )));
+        assert!(is_restarting(&DeviceError::LockdownError(
+            LockdownError::UsbmuxOperationError(UsbmuxOperationError::DeviceNotFound(
+                "00008030-001A".to_string()
+            ))
+        )));
+        assert!(!is_restarting(&DeviceError::LockdownError(
+            LockdownError::PasswordProtected
+        )));
+        assert!(!is_restarting(&DeviceError::UsbMuxOperationError(
+            UsbmuxOperationError::PairRecordError(PairRecordError::NotFound(
+                "00008030-001A".to_string()
+            ))
+        )));
+    }
 }
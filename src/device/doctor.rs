@@ -0,0 +1,307 @@
+use std::fmt::Display;
+use std::path::Path;
+
+use super::{Device, DeviceConnection, TunnelMode};
+use crate::lockdown::errors::LockdownError;
+use crate::lockdown::LockdownClient;
+use crate::tunnel;
+
+const USBMUXD: &str = "usbmuxd reachable";
+const DEVICE_LISTED: &str = "Device listed";
+const PAIR_RECORD: &str = "Pair record present";
+const LOCKDOWN_SESSION: &str = "Lockdown session";
+const IOS_VERSION: &str = "iOS 17 or later";
+const DEVELOPER_MODE: &str = "Developer mode on";
+const TUNNEL_BACKEND: &str = "Tunnel backend";
+const RSD: &str = "RSD reachable";
+
+/// The checks run by `doctor`, in order.
+const CHECKS: [&str; 8] = [
+    USBMUXD,
+    DEVICE_LISTED,
+    PAIR_RECORD,
+    LOCKDOWN_SESSION,
+    IOS_VERSION,
+    DEVELOPER_MODE,
+    TUNNEL_BACKEND,
+    RSD,
+];
+
+#[cfg(windows)]
+const TUNNEL_BACKEND_HINT: &str =
+    "Download wintun.dll from https://www.wintun.net/, place it next to the executable and run as administrator, or run `tunnel-mode userspace`";
+#[cfg(not(windows))]
+const TUNNEL_BACKEND_HINT: &str =
+    "Run as root or grant CAP_NET_ADMIN, e.g. `sudo setcap cap_net_admin+ep <executable>`, or run `tunnel-mode userspace`";
+
+/// Lowest iOS major version with the CoreDeviceProxy tunnel.
+const MIN_IOS_VERSION: u32 = 17;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum CheckStatus {
+    Pass,
+    Fail,
+    /// Not run because an earlier check it depends on failed.
+    Skipped,
+}
+
+/// Outcome of one readiness check.
+#[derive(Debug, Clone)]
+pub struct Check {
+    pub name: &'static str,
+    pub status: CheckStatus,
+    /// What was found, or why the check failed.
+    pub detail: String,
+    /// How to fix a failure.
+    pub hint: Option<&'static str>,
+}
+
+#[derive(Default)]
+struct Report {
+    checks: Vec<Check>,
+}
+
+impl Report {
+    fn pass(&mut self, name: &'static str, detail: impl Display) {
+        self.checks.push(Check {
+            name,
+            status: CheckStatus::Pass,
+            detail: detail.to_string(),
+            hint: None,
+        });
+    }
+
+    fn fail(&mut self, name: &'static str, detail: impl Display, hint: &'static str) {
+        self.checks.push(Check {
+            name,
+            status: CheckStatus::Fail,
+            detail: detail.to_string(),
+            hint: Some(hint),
+        });
+    }
+
+    fn skip(&mut self, name: &'static str, detail: impl Display, hint: Option<&'static str>) {
+        self.checks.push(Check {
+            name,
+            status: CheckStatus::Skipped,
+            detail: detail.to_string(),
+            hint,
+        });
+    }
+
+    /// Marks the checks that did not run as skipped and returns them all in
+    /// `CHECKS` order.
+    fn finish(mut self) -> Vec<Check> {
+        for name in CHECKS {
+            if !self.checks.iter().any(|check| check.name == name) {
+                self.skip(name, "an earlier check failed", None);
+            }
+        }
+        self.checks.sort_by_key(|check| {
+            CHECKS
+                .iter()
+                .position(|name| *name == check.name)
+                .unwrap_or(CHECKS.len())
+        });
+        self.checks
+    }
+}
+
+impl Device {
+    /// Runs the prerequisites of `connect` one by one for the device with the
+    /// given UDID, or the first attached device, stopping the device checks
+    /// at the first failure. The tunnel backend is checked regardless.
+    pub async fn doctor(&self, wintun_path: &Path, udid: Option<&str>) -> Vec<Check> {
+        let mut report = Report::default();
+
+        match self.tunnel_mode {
+            TunnelMode::Userspace => report.pass(TUNNEL_BACKEND, "userspace stack"),
+            TunnelMode::Interface => match tunnel::check_backend(wintun_path) {
+                Ok(backend) => report.pass(TUNNEL_BACKEND, backend),
+                Err(error) => report.fail(TUNNEL_BACKEND, error, TUNNEL_BACKEND_HINT),
+            },
+        }
+
+        self.check_device(&mut report, udid).await;
+        report.finish()
+    }
+
+    async fn check_device(&self, report: &mut Report, udid: Option<&str>) {
+        let mut usbmux_client = match self.usbmux_client().await {
+            Ok(client) => {
+                match self.device_connection {
+                    DeviceConnection::Usbmux => report.pass(USBMUXD, "connected"),
+                    DeviceConnection::Direct(address) => {
+                        report.skip(USBMUXD, format!("not used, direct to {}", address), None)
+                    }
+                }
+                client
+            }
+            Err(error) => {
+                return report.fail(
+                    USBMUXD,
+                    error,
+                    "Install Apple Devices or iTunes on Windows, start usbmuxd elsewhere",
+                )
+            }
+        };
+
+        match usbmux_client.select_device(udid).await {
+            Ok(info) => report.pass(
+                DEVICE_LISTED,
+                format!("{} ({:?})", info.serial_number, info.connection_type),
+            ),
+            Err(error) => {
+                return report.fail(
+                    DEVICE_LISTED,
+                    error,
+                    "Connect the device with a USB cable and unlock it; check the UDID with list-devices",
+                )
+            }
+        }
+
+        match usbmux_client.get_device_pair_record().await {
+            Ok(()) => report.pass(PAIR_RECORD, "found"),
+            Err(error) => {
+                return report.fail(
+                    PAIR_RECORD,
+                    error,
+                    "Run `pair` and tap \"Trust\" on the device, or check `pair-records`",
+                )
+            }
+        }
+
+        let session = match LockdownClient::connect(usbmux_client).await {
+            Ok(mut lockdown) => lockdown.start_session().await.map(|()| lockdown),
+            Err(error) => Err(error),
+        };
+        let mut lockdown = match session {
+            Ok(lockdown) => lockdown,
+            Err(error) => {
+                let hint = session_hint(&error);
+                return report.fail(LOCKDOWN_SESSION, error, hint);
+            }
+        };
+        let info = match lockdown.device_info().await {
+            Ok(info) => {
+                report.pass(
+                    LOCKDOWN_SESSION,
+                    format!(
+                        "{} ({})",
+                        info.device_name.as_deref().unwrap_or("unnamed"),
+                        info.product_type
+                    ),
+                );
+                info
+            }
+            Err(error) => {
+                return report.fail(LOCKDOWN_SESSION, error, "Unlock the device and try again")
+            }
+        };
+
+        match ios_major_version(&info.product_version) {
+            Some(major) if major >= MIN_IOS_VERSION => {
+                report.pass(IOS_VERSION, &info.product_version)
+            }
+            _ => {
+                return report.fail(
+                    IOS_VERSION,
+                    &info.product_version,
+                    "Update the device to iOS 17 or later",
+                )
+            }
+        }
+
+        match lockdown.developer_mode_status().await {
+            Ok(true) => report.pass(DEVELOPER_MODE, "enabled"),
+            Ok(false) => {
+                return report.fail(
+                    DEVELOPER_MODE,
+                    "disabled",
+                    "Run `reveal-developer-mode` and turn it on in Settings, or `enable-developer-mode`",
+                )
+            }
+            Err(error) => {
+                return report.fail(
+                    DEVELOPER_MODE,
+                    error,
+                    "Run `reveal-developer-mode` and turn it on in Settings",
+                )
+            }
+        }
+
+        if self.device_addr.is_none() {
+            return report.skip(RSD, "no tunnel", Some("Run `connect`, then `doctor` again"));
+        }
+        match self.get_dt_service_port().await {
+            Ok(port) => report.pass(RSD, format!("DVT service on port {}", port)),
+            Err(error) => report.fail(
+                RSD,
+                error,
+                "Run `connect` again; the tunnel may have dropped",
+            ),
+        }
+    }
+}
+
+/// How to fix a lockdown session that failed with `error`.
+fn session_hint(error: &LockdownError) -> &'static str {
+    match error {
+        LockdownError::InvalidHostID => "Run `unpair` and `pair` again",
+        LockdownError::PasswordProtected => "Unlock the device and try again",
+        _ => "Unlock the device; if it keeps failing, run `unpair` and `pair` again",
+    }
+}
+
+/// Parses the major version of an iOS version such as `17.4.1`.
+fn ios_major_version(version: &str) -> Option<u32> {
+    version.split('.').next()?.parse().ok()
+}
+
+#[cfg(test)]
+mod test {
+    use super::{
+        ios_major_version, session_hint, CheckStatus, Report, CHECKS, DEVICE_LISTED,
+        TUNNEL_BACKEND, USBMUXD,
+    };
+    use crate::lockdown::errors::LockdownError;
+
+    #[test]
+    fn test_report_order() {
+        let mut report = Report::default();
+        report.fail(TUNNEL_BACKEND, "missing", "hint");
+        report.pass(USBMUXD, "connected");
+        report.fail(DEVICE_LISTED, "no devices", "hint");
+        let checks = report.finish();
+
+        assert_eq!(
+            checks.iter().map(|check| check.name).collect::<Vec<_>>(),
+            CHECKS
+        );
+        assert_eq!(checks[0].status, CheckStatus::Pass);
+        assert_eq!(checks[1].status, CheckStatus::Fail);
+        assert_eq!(checks[2].status, CheckStatus::Skipped);
+        assert_eq!(checks[6].status, CheckStatus::Fail);
+    }
+
+    #[test]
+    fn test_session_hint() {
+        let repair = session_hint(&LockdownError::from_response("InvalidHostID".to_string()));
+        assert!(repair.contains("`pair`"));
+        let locked = session_hint(&LockdownError::from_response(
+            "PasswordProtected".to_string(),
+        ));
+        assert_eq!(locked, "Unlock the device and try again");
+        assert_ne!(
+            session_hint(&LockdownError::from_response("SessionInactive".to_string())),
+            repair
+        );
+    }
+
+    #[test]
+    fn test_ios_major_version() {
+        assert_eq!(ios_major_version("17.4.1"), Some(17));
+        assert_eq!(ios_major_version("16"), Some(16));
+        assert_eq!(ios_major_version(""), None);
+    }
+}
@@ -8,6 +8,16 @@ pub enum DeviceError {
     Error(&'static str),
     #[error("XPC error: {0}")]
     XpcError(#[from] crate::xpc::errors::XpcError),
+    #[error("Lockdown error: {0}")]
+    LockdownError(#[from] crate::lockdown::errors::LockdownError),
+    #[error("Pair record error: {0}")]
+    PairRecordError(#[from] crate::usbmux::errors::PairRecordError),
+    #[error("AMFI error: {0}")]
+    AmfiError(#[from] crate::amfi::errors::AmfiError),
+    #[error("Tunnel error: {0}")]
+    TunnelError(#[from] crate::tunnel::errors::TunnelError),
+    #[error("Forward error: {0}")]
+    ForwardError(#[from] crate::forward::errors::ForwardError),
     #[error("DtService error: {0}")]
     DtServiceError(#[from] crate::dtservice::errors::DtServiceError),
 }
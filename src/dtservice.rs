@@ -11,14 +11,31 @@ use tokio::{
     net::TcpStream,
 };
 
+use crate::transport::BoxedTransport;
+
+/// Default upper bound for the payload of a received DTX message.
+pub const DEFAULT_MAX_DTX_MESSAGE_SIZE: usize = 16 << 20;
+
+const DTX_MAGIC: [u8; 4] = [0x79, 0x5B, 0x3D, 0x1F];
+
 pub struct DtServiceHandler {
-    sock: TcpStream,
+    sock: BoxedTransport,
+    /// Largest DTX payload accepted from the device.
+    pub max_message_size: usize,
 }
 
 impl DtServiceHandler {
     pub async fn new(server_addr: &String, server_port: &u16) -> Result<Self, DtServiceError> {
         let sock = TcpStream::connect(format!("[{}]:{}", server_addr, server_port)).await?;
-        Ok(DtServiceHandler { sock: sock })
+        Ok(DtServiceHandler::from_transport(Box::new(sock)))
+    }
+
+    /// Creates a handler running over an already connected transport.
+    pub fn from_transport(sock: BoxedTransport) -> Self {
+        DtServiceHandler {
+            sock: sock,
+            max_message_size: DEFAULT_MAX_DTX_MESSAGE_SIZE,
+        }
     }
 
     pub async fn do_handshake(&mut self) -> Result<(), DtServiceError> {
@@ -50,16 +67,86 @@ impl DtServiceHandler {
 
         self.sock.read_exact(&mut dxt_msg_header).await?;
 
+        if dxt_msg_header[..4] != DTX_MAGIC {
+            return Err(DtServiceError::InvalidHeader);
+        }
+
         let mut cursor = Cursor::new(&dxt_msg_header);
 
         cursor.set_position(12);
 
-        let mut length = byteorder::ReadBytesExt::read_u32::<LittleEndian>(&mut cursor)?;
+        let length = byteorder::ReadBytesExt::read_u32::<LittleEndian>(&mut cursor)? as usize;
+        if length > self.max_message_size {
+            return Err(DtServiceError::FrameTooLarge {
+                length,
+                max: self.max_message_size,
+            });
+        }
 
-        let mut payload = vec![0u8; length as usize];
+        let mut payload = vec![0u8; length];
 
         self.sock.read_exact(&mut payload).await?;
 
         Ok(())
     }
 }
+
+#[cfg(test)]
+mod test {
+    use tokio::io::{AsyncReadExt, AsyncWriteExt};
+
+    use super::{errors::DtServiceError, DtServiceHandler, DTX_MAGIC};
+
+    #[tokio::test]
+    async fn test_simulate_location() {
+        let (client_end, mut server) = tokio::io::duplex(4096);
+        let mut handler = DtServiceHandler::from_transport(Box::new(client_end));
+
+        let device = tokio::spawn(async move {
+            let mut request = [0u8; 559];
+            server.read_exact(&mut request).await.unwrap();
+            assert_eq!(&request[154..162], &40.5f64.to_be_bytes());
+            assert_eq!(&request[312..320], &(-3.5f64).to_be_bytes());
+
+            let mut reply = [0u8; 36];
+            reply[..4].copy_from_slice(&DTX_MAGIC);
+            reply[12..16].copy_from_slice(&4u32.to_le_bytes());
+            server.write_all(&reply).await.unwrap();
+        });
+
+        handler.simulate_location(40.5, -3.5).await.unwrap();
+        device.await.unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_malformed_replies() {
+        let (client_end, mut server) = tokio::io::duplex(4096);
+        let mut handler = DtServiceHandler::from_transport(Box::new(client_end));
+        handler.max_message_size = 1024;
+
+        let mut reply = [0u8; 32];
+        reply[..4].copy_from_slice(&DTX_MAGIC);
+        reply[12..16].copy_from_slice(&u32::MAX.to_le_bytes());
+        server.write_all(&reply).await.unwrap();
+        assert!(matches!(
+            handler.receive_dxt_message().await,
+            Err(DtServiceError::FrameTooLarge {
+                length: 0xffff_ffff,
+                max: 1024
+            })
+        ));
+
+        server.write_all(&[0u8; 32]).await.unwrap();
+        assert!(matches!(
+            handler.receive_dxt_message().await,
+            Err(DtServiceError::InvalidHeader)
+        ));
+
+        server.write_all(&DTX_MAGIC).await.unwrap();
+        drop(server);
+        assert!(matches!(
+            handler.receive_dxt_message().await,
+            Err(DtServiceError::Io(_))
+        ));
+    }
+}
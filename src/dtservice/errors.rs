@@ -2,4 +2,8 @@
 pub enum DtServiceError {
     #[error("IO error: {0}")]
     Io(#[from] std::io::Error),
+    #[error("Invalid DTX message header")]
+    InvalidHeader,
+    #[error("DTX message of {length} bytes exceeds the {max} byte limit")]
+    FrameTooLarge { length: usize, max: usize },
 }
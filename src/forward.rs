@@ -0,0 +1,304 @@
+pub mod errors;
+pub mod socks;
+
+use std::{
+    fmt, io,
+    net::{Ipv6Addr, SocketAddr},
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        Arc,
+    },
+};
+
+use log::{error, info};
+use tokio::{
+    io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt},
+    net::{TcpListener, TcpStream},
+    task::{JoinHandle, JoinSet},
+};
+
+use crate::transport::BoxedTransport;
+use crate::tunnel::Dialer;
+use crate::usbmux::device_info::ConnectionType;
+use crate::usbmux::{DeviceConnection, UsbMuxClient};
+use errors::ForwardError;
+
+/// Where a [`Forward`] relays connections to.
+#[derive(Clone)]
+pub enum ForwardTarget {
+    /// A port on the device's address in the CoreDeviceProxy tunnel.
+    Tunnel {
+        dialer: Dialer,
+        address: Ipv6Addr,
+        port: u16,
+    },
+    /// A port on the device, through a new usbmuxd `Connect` each time, or a
+    /// new TCP connection for direct devices.
+    Usbmux {
+        device_connection: DeviceConnection,
+        connection_type: Option<ConnectionType>,
+        udid: String,
+        port: u16,
+    },
+    /// Any port on an address in the tunnel's /64, chosen by each client
+    /// with a SOCKS5 `CONNECT` request.
+    Socks {
+        dialer: Dialer,
+        /// The tunnel prefix; only its first 64 bits are used.
+        network: Ipv6Addr,
+    },
+}
+
+impl ForwardTarget {
+    /// Opens a connection to the target for the local `client`. SOCKS
+    /// targets first read the destination from the client.
+    pub async fn open<S>(&self, client: &mut S) -> Result<BoxedTransport, ForwardError>
+    where
+        S: AsyncRead + AsyncWrite + Unpin,
+    {
+        match self {
+            ForwardTarget::Tunnel {
+                dialer,
+                address,
+                port,
+            } => Ok(dialer.connect(*address, *port).await?),
+            ForwardTarget::Usbmux {
+                device_connection,
+                connection_type,
+                udid,
+                port,
+            } => {
+                let mut client = match device_connection {
+                    DeviceConnection::Usbmux => UsbMuxClient::new().await?,
+                    DeviceConnection::Direct(address) => UsbMuxClient::direct(*address),
+                };
+                client.connection_type = connection_type.clone();
+                client.select_device(Some(udid)).await?;
+                client.connect_to_service(*port).await?;
+                client
+                    .sock
+                    .take()
+                    .ok_or_else(|| io::Error::from(io::ErrorKind::NotConnected).into())
+            }
+            ForwardTarget::Socks { dialer, network } => {
+                socks::handshake(client, dialer, *network).await
+            }
+        }
+    }
+}
+
+impl fmt::Display for ForwardTarget {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ForwardTarget::Tunnel { address, port, .. } => write!(f, "[{}]:{}", address, port),
+            ForwardTarget::Usbmux { udid, port, .. } => write!(f, "{}:{} (usbmux)", udid, port),
+            ForwardTarget::Socks { network, .. } => {
+                write!(f, "{}/64 (SOCKS5)", socks::prefix(*network))
+            }
+        }
+    }
+}
+
+/// Traffic through a [`Forward`], updated as bytes are relayed.
+#[derive(Debug, Default)]
+pub struct ForwardCounters {
+    /// Bytes relayed from local clients to the device.
+    pub sent: AtomicU64,
+    /// Bytes relayed from the device to local clients.
+    pub received: AtomicU64,
+    /// Connections accepted so far.
+    pub connections: AtomicU64,
+    /// Connections currently open.
+    pub active: AtomicU64,
+}
+
+/// Relays each connection accepted on a local port to a port on the device.
+/// Stops when dropped.
+pub struct Forward {
+    /// Address the forward listens on.
+    pub local_addr: SocketAddr,
+    pub target: ForwardTarget,
+    pub counters: Arc<ForwardCounters>,
+    handle: JoinHandle<()>,
+}
+
+impl Forward {
+    /// Listens on `local_addr` and relays every accepted connection to
+    /// `target`.
+    pub async fn start(
+        local_addr: SocketAddr,
+        target: ForwardTarget,
+    ) -> Result<Forward, ForwardError> {
+        let listener = TcpListener::bind(local_addr).await?;
+        let local_addr = listener.local_addr()?;
+        let counters = Arc::new(ForwardCounters::default());
+
+        let task_target = target.clone();
+        let task_counters = counters.clone();
+        let handle = tokio::spawn(async move {
+            // Dropped with the accept loop, which closes open connections.
+            let mut connections = JoinSet::new();
+            loop {
+                tokio::select! {
+                    accepted = listener.accept() => {
+                        let (mut client, peer) = match accepted {
+                            Ok(accepted) => accepted,
+                            Err(e) => {
+                                error!("Forward on {} stopped: {}", local_addr, e);
+                                break;
+                            }
+                        };
+                        let target = task_target.clone();
+                        let counters = task_counters.clone();
+                        connections.spawn(async move {
+                            counters.connections.fetch_add(1, Ordering::Relaxed);
+                            counters.active.fetch_add(1, Ordering::Relaxed);
+                            match target.open(&mut client).await {
+                                Ok(device) => {
+                                    info!("Forwarding {} to {}", peer, target);
+                                    relay(client, device, &counters).await;
+                                }
+                                Err(e) => error!("Forward to {} failed: {}", target, e),
+                            }
+                            counters.active.fetch_sub(1, Ordering::Relaxed);
+                        });
+                    },
+                    Some(_) = connections.join_next() => {}
+                }
+            }
+        });
+
+        Ok(Forward {
+            local_addr,
+            target,
+            counters,
+            handle,
+        })
+    }
+
+    /// Stops listening and closes the connections being relayed.
+    pub fn stop(&self) {
+        self.handle.abort();
+    }
+}
+
+impl Drop for Forward {
+    fn drop(&mut self) {
+        self.handle.abort();
+    }
+}
+
+/// Copies both ways between a local client and the device until both sides
+/// are done.
+async fn relay<D>(client: TcpStream, device: D, counters: &ForwardCounters)
+where
+    D: AsyncRead + AsyncWrite,
+{
+    let (mut client_reader, mut client_writer) = client.into_split();
+    let (mut device_reader, mut device_writer) = tokio::io::split(device);
+    let upstream = copy_counted(&mut client_reader, &mut device_writer, &counters.sent);
+    let downstream = copy_counted(&mut device_reader, &mut client_writer, &counters.received);
+    let _ = tokio::join!(upstream, downstream);
+}
+
+/// Copies `reader` to `writer` until end of file, adding the bytes copied to
+/// `counter` as they go, then shuts `writer` down.
+async fn copy_counted<R, W>(reader: &mut R, writer: &mut W, counter: &AtomicU64) -> io::Result<()>
+where
+    R: AsyncRead + Unpin,
+    W: AsyncWrite + Unpin,
+{
+    let mut buffer = vec![0u8; 16 * 1024];
+    loop {
+        let length = reader.read(&mut buffer).await?;
+        if length == 0 {
+            break;
+        }
+        writer.write_all(&buffer[..length]).await?;
+        counter.fetch_add(length as u64, Ordering::Relaxed);
+    }
+    writer.shutdown().await
+}
+
+#[cfg(test)]
+mod test {
+    use std::{
+        net::{IpAddr, Ipv6Addr},
+        sync::atomic::Ordering,
+    };
+
+    use tokio::{
+        io::{AsyncReadExt, AsyncWriteExt},
+        net::{TcpListener, TcpStream},
+    };
+
+    use super::{Forward, ForwardTarget};
+    use crate::tunnel::Dialer;
+    use crate::usbmux::DeviceConnection;
+
+    /// Stands in for a service on the device, answering one ping. Returns
+    /// its port.
+    async fn pong_service() -> u16 {
+        let service = TcpListener::bind((Ipv6Addr::LOCALHOST, 0)).await.unwrap();
+        let port = service.local_addr().unwrap().port();
+        tokio::spawn(async move {
+            let (mut sock, _) = service.accept().await.unwrap();
+            let mut request = [0u8; 4];
+            sock.read_exact(&mut request).await.unwrap();
+            sock.write_all(b"pong!").await.unwrap();
+        });
+        port
+    }
+
+    /// Pings through `forward` and returns the reply.
+    async fn ping(forward: &Forward) -> Vec<u8> {
+        let mut client = TcpStream::connect(forward.local_addr).await.unwrap();
+        client.write_all(b"ping").await.unwrap();
+        let mut reply = Vec::new();
+        client.read_to_end(&mut reply).await.unwrap();
+        reply
+    }
+
+    #[tokio::test]
+    async fn test_forward() {
+        let service_port = pong_service().await;
+        let target = ForwardTarget::Tunnel {
+            dialer: Dialer::Interface,
+            address: Ipv6Addr::LOCALHOST,
+            port: service_port,
+        };
+        assert_eq!(target.to_string(), format!("[::1]:{}", service_port));
+        let forward = Forward::start("127.0.0.1:0".parse().unwrap(), target)
+            .await
+            .unwrap();
+
+        assert_eq!(ping(&forward).await, b"pong!");
+
+        assert_eq!(forward.counters.sent.load(Ordering::Relaxed), 4);
+        assert_eq!(forward.counters.received.load(Ordering::Relaxed), 5);
+        assert_eq!(forward.counters.connections.load(Ordering::Relaxed), 1);
+
+        forward.stop();
+    }
+
+    #[tokio::test]
+    async fn test_usbmux_forward_direct() {
+        let service_port = pong_service().await;
+        let target = ForwardTarget::Usbmux {
+            device_connection: DeviceConnection::Direct(IpAddr::V6(Ipv6Addr::LOCALHOST)),
+            connection_type: None,
+            udid: "00008030-001A2B3C4D5E802E".to_string(),
+            port: service_port,
+        };
+        assert_eq!(
+            target.to_string(),
+            format!("00008030-001A2B3C4D5E802E:{} (usbmux)", service_port)
+        );
+        let forward = Forward::start("127.0.0.1:0".parse().unwrap(), target)
+            .await
+            .unwrap();
+
+        assert_eq!(ping(&forward).await, b"pong!");
+        assert_eq!(forward.counters.received.load(Ordering::Relaxed), 5);
+    }
+}
@@ -0,0 +1,14 @@
+use crate::tunnel::errors::TunnelError;
+use crate::usbmux::errors::UsbmuxOperationError;
+
+#[derive(Debug, thiserror::Error)]
+pub enum ForwardError {
+    #[error("IO error: {0}")]
+    Io(#[from] std::io::Error),
+    #[error("Tunnel error: {0}")]
+    TunnelError(#[from] TunnelError),
+    #[error("UsbmuxOperation error: {0}")]
+    UsbmuxOperationError(#[from] UsbmuxOperationError),
+    #[error("SOCKS error: {0}")]
+    Socks(&'static str),
+}
@@ -0,0 +1,194 @@
+use std::net::Ipv6Addr;
+
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt};
+
+use super::errors::ForwardError;
+use crate::transport::BoxedTransport;
+use crate::tunnel::{errors::TunnelError, Dialer};
+
+const VERSION: u8 = 0x05;
+const NO_AUTHENTICATION: u8 = 0x00;
+const NO_ACCEPTABLE_METHODS: u8 = 0xff;
+const CONNECT: u8 = 0x01;
+const ADDRESS_IPV4: u8 = 0x01;
+const ADDRESS_DOMAIN: u8 = 0x03;
+const ADDRESS_IPV6: u8 = 0x04;
+
+// Reply codes
+const SUCCEEDED: u8 = 0x00;
+const GENERAL_FAILURE: u8 = 0x01;
+const NOT_ALLOWED: u8 = 0x02;
+const NETWORK_UNREACHABLE: u8 = 0x03;
+const HOST_UNREACHABLE: u8 = 0x04;
+const CONNECTION_REFUSED: u8 = 0x05;
+const COMMAND_NOT_SUPPORTED: u8 = 0x07;
+const ADDRESS_NOT_SUPPORTED: u8 = 0x08;
+
+/// Returns `address` with everything after the first 64 bits cleared.
+pub fn prefix(address: Ipv6Addr) -> Ipv6Addr {
+    Ipv6Addr::from(u128::from(address) & !(u64::MAX as u128))
+}
+
+/// Runs the SOCKS5 handshake with `client`, without authentication, and
+/// connects to the requested destination through `dialer`. Only `CONNECT`
+/// requests to IPv6 addresses in the /64 of `network` are accepted; IPv6
+/// literals given as domain names count too.
+pub async fn handshake<S>(
+    client: &mut S,
+    dialer: &Dialer,
+    network: Ipv6Addr,
+) -> Result<BoxedTransport, ForwardError>
+where
+    S: AsyncRead + AsyncWrite + Unpin,
+{
+    let mut header = [0u8; 2];
+    client.read_exact(&mut header).await?;
+    if header[0] != VERSION {
+        return Err(ForwardError::Socks("not a SOCKS5 client"));
+    }
+    let mut methods = vec![0u8; header[1] as usize];
+    client.read_exact(&mut methods).await?;
+    if !methods.contains(&NO_AUTHENTICATION) {
+        client.write_all(&[VERSION, NO_ACCEPTABLE_METHODS]).await?;
+        return Err(ForwardError::Socks("client requires authentication"));
+    }
+    client.write_all(&[VERSION, NO_AUTHENTICATION]).await?;
+
+    let mut request = [0u8; 4];
+    client.read_exact(&mut request).await?;
+    if request[0] != VERSION {
+        return Err(ForwardError::Socks("not a SOCKS5 client"));
+    }
+    let address = match request[3] {
+        ADDRESS_IPV6 => {
+            let mut octets = [0u8; 16];
+            client.read_exact(&mut octets).await?;
+            Some(Ipv6Addr::from(octets))
+        }
+        ADDRESS_IPV4 => {
+            client.read_exact(&mut [0u8; 4]).await?;
+            None
+        }
+        ADDRESS_DOMAIN => {
+            let length = client.read_u8().await?;
+            let mut domain = vec![0u8; length as usize];
+            client.read_exact(&mut domain).await?;
+            std::str::from_utf8(&domain)
+                .ok()
+                .map(|domain| domain.trim_start_matches('[').trim_end_matches(']'))
+                .and_then(|domain| domain.parse().ok())
+        }
+        _ => return Err(ForwardError::Socks("unknown address type")),
+    };
+    let port = client.read_u16().await?;
+
+    if request[1] != CONNECT {
+        reply(client, COMMAND_NOT_SUPPORTED).await?;
+        return Err(ForwardError::Socks("only CONNECT is supported"));
+    }
+    let Some(address) = address else {
+        reply(client, ADDRESS_NOT_SUPPORTED).await?;
+        return Err(ForwardError::Socks("only IPv6 destinations are supported"));
+    };
+    if prefix(address) != prefix(network) {
+        reply(client, NOT_ALLOWED).await?;
+        return Err(ForwardError::Socks(
+            "destination outside the tunnel network",
+        ));
+    }
+
+    match dialer.connect(address, port).await {
+        Ok(device) => {
+            reply(client, SUCCEEDED).await?;
+            Ok(device)
+        }
+        Err(error) => {
+            let code = match &error {
+                TunnelError::Closed => NETWORK_UNREACHABLE,
+                TunnelError::Io(e) => match e.kind() {
+                    std::io::ErrorKind::ConnectionRefused => CONNECTION_REFUSED,
+                    std::io::ErrorKind::TimedOut => HOST_UNREACHABLE,
+                    _ => GENERAL_FAILURE,
+                },
+                _ => GENERAL_FAILURE,
+            };
+            reply(client, code).await?;
+            Err(error.into())
+        }
+    }
+}
+
+/// Sends a reply with an unspecified bound address.
+async fn reply<S: AsyncWrite + Unpin>(client: &mut S, code: u8) -> Result<(), ForwardError> {
+    let mut message = vec![VERSION, code, 0x00, ADDRESS_IPV6];
+    message.extend_from_slice(&Ipv6Addr::UNSPECIFIED.octets());
+    message.extend_from_slice(&0u16.to_be_bytes());
+    client.write_all(&message).await?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod test {
+    use std::net::Ipv6Addr;
+
+    use tokio::{
+        io::{AsyncReadExt, AsyncWriteExt},
+        net::TcpListener,
+    };
+
+    use super::{handshake, prefix};
+    use crate::forward::errors::ForwardError;
+    use crate::tunnel::Dialer;
+
+    /// Sends a `CONNECT` request for `[address]:port` and returns the reply
+    /// code, along with what the proxy side returned.
+    async fn connect(address: Ipv6Addr, port: u16) -> (u8, Result<(), ForwardError>) {
+        let (mut client, mut proxy) = tokio::io::duplex(1024);
+        let proxy = tokio::spawn(async move {
+            handshake(&mut proxy, &Dialer::Interface, Ipv6Addr::UNSPECIFIED)
+                .await
+                .map(|_| ())
+        });
+
+        client.write_all(&[5, 2, 0x02, 0x00]).await.unwrap();
+        let mut method = [0u8; 2];
+        client.read_exact(&mut method).await.unwrap();
+        assert_eq!(method, [5, 0]);
+
+        let mut request = vec![5, 1, 0, 4];
+        request.extend_from_slice(&address.octets());
+        request.extend_from_slice(&port.to_be_bytes());
+        client.write_all(&request).await.unwrap();
+        let mut reply = [0u8; 22];
+        client.read_exact(&mut reply).await.unwrap();
+        (reply[1], proxy.await.unwrap())
+    }
+
+    #[tokio::test]
+    async fn test_socks_handshake() {
+        let service = TcpListener::bind((Ipv6Addr::LOCALHOST, 0)).await.unwrap();
+        let port = service.local_addr().unwrap().port();
+
+        let (code, result) = connect(Ipv6Addr::LOCALHOST, port).await;
+        assert_eq!(code, 0x00);
+        assert!(result.is_ok());
+
+        let outside = "fd00::1".parse().unwrap();
+        let (code, result) = connect(outside, port).await;
+        assert_eq!(code, 0x02);
+        assert!(matches!(result, Err(ForwardError::Socks(_))));
+
+        drop(service);
+        let (code, _) = connect(Ipv6Addr::LOCALHOST, port).await;
+        assert_eq!(code, 0x05);
+    }
+
+    #[test]
+    fn test_prefix() {
+        let address: Ipv6Addr = "fd35:d15b:2c3d:1::2".parse().unwrap();
+        assert_eq!(
+            prefix(address),
+            "fd35:d15b:2c3d:1::".parse::<Ipv6Addr>().unwrap()
+        );
+    }
+}
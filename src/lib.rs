@@ -1,7 +1,11 @@
 #![allow(warnings)]
 
+mod amfi;
 pub mod device;
 mod dtservice;
+mod forward;
+mod lockdown;
+pub mod transport;
 mod tunnel;
 mod usbmux;
 mod xpc;
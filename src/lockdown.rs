@@ -0,0 +1,657 @@
+pub mod device_info;
+pub mod errors;
+pub mod heartbeat;
+pub mod pairing;
+pub mod service;
+
+use std::time::{Duration, Instant};
+
+use device_info::DeviceInfo;
+use errors::LockdownError;
+use pairing::{generate_pair_record, pair_request_record, PAIRING_PROTOCOL_VERSION};
+use plist::{Data, Dictionary, Value};
+use serde::de::DeserializeOwned;
+use service::{ServiceConnection, SslMode};
+use uuid::Uuid;
+
+use crate::usbmux::message::{
+    GetValueResponse, LockdownRequest, LockdownResponse, PairResponse, QueryTypeResponse,
+    StartServiceResponse, StartSessionResponse,
+};
+use crate::usbmux::pair_record::PairRecord;
+use crate::usbmux::UsbMuxClient;
+
+/// Port lockdownd listens on.
+pub const LOCKDOWN_PORT: u16 = 62078;
+/// Domain of the Wi-Fi connection settings.
+const WIRELESS_LOCKDOWN_DOMAIN: &str = "com.apple.mobile.wireless_lockdown";
+
+/// A connection to a device's lockdownd, running over a usbmux connection.
+pub struct LockdownClient {
+    /// The underlying connection, carrying the host identity of the pair
+    /// record in use.
+    pub usbmux: UsbMuxClient,
+    /// ID of the running session, if any.
+    pub session_id: Option<String>,
+    /// Whether the session switched the connection to TLS.
+    pub session_ssl: bool,
+}
+
+impl LockdownClient {
+    /// Connects `usbmux`, which must have a device selected, to the device's
+    /// lockdownd.
+    pub async fn connect(mut usbmux: UsbMuxClient) -> Result<Self, LockdownError> {
+        usbmux.connect_to_service(LOCKDOWN_PORT).await?;
+        Ok(LockdownClient::from_client(usbmux))
+    }
+
+    /// Creates a client over a usbmux connection already talking to lockdownd.
+    pub fn from_client(usbmux: UsbMuxClient) -> Self {
+        LockdownClient {
+            usbmux,
+            session_id: None,
+            session_ssl: false,
+        }
+    }
+
+    /// Returns the underlying connection.
+    pub fn into_inner(self) -> UsbMuxClient {
+        self.usbmux
+    }
+
+    async fn request<R: DeserializeOwned>(
+        &mut self,
+        request: &LockdownRequest,
+    ) -> Result<R, LockdownError> {
+        Ok(self.usbmux.lockdown_request(request).await?)
+    }
+
+    /// Returns the service type, `com.apple.mobile.lockdown` for lockdownd.
+    pub async fn query_type(&mut self) -> Result<String, LockdownError> {
+        let response: QueryTypeResponse = self.request(&LockdownRequest::QueryType).await?;
+        check_response(response.error)?;
+        response
+            .service_type
+            .ok_or_else(|| LockdownError::MissingValue("Type".to_string()))
+    }
+
+    /// Reads a value. `domain` `None` is the global domain and `key` `None`
+    /// returns the whole domain as a dictionary.
+    pub async fn get_value<T: DeserializeOwned>(
+        &mut self,
+        domain: Option<&str>,
+        key: Option<&str>,
+    ) -> Result<T, LockdownError> {
+        let request = LockdownRequest::GetValue {
+            domain: domain.map(|domain| domain.to_string()),
+            key: key.map(|key| key.to_string()),
+        };
+        let response: GetValueResponse<T> = self.request(&request).await?;
+        check_response(response.error)?;
+        response
+            .value
+            .ok_or_else(|| LockdownError::MissingValue(key.unwrap_or_default().to_string()))
+    }
+
+    /// Writes a value. Most domains require a session.
+    pub async fn set_value(
+        &mut self,
+        domain: Option<&str>,
+        key: &str,
+        value: impl Into<Value>,
+    ) -> Result<(), LockdownError> {
+        let request = LockdownRequest::SetValue {
+            domain: domain.map(|domain| domain.to_string()),
+            key: Some(key.to_string()),
+            value: value.into(),
+        };
+        let response: LockdownResponse = self.request(&request).await?;
+        check_response(response.error)
+    }
+
+    /// Removes a value. Most domains require a session.
+    pub async fn remove_value(
+        &mut self,
+        domain: Option<&str>,
+        key: &str,
+    ) -> Result<(), LockdownError> {
+        let request = LockdownRequest::RemoveValue {
+            domain: domain.map(|domain| domain.to_string()),
+            key: Some(key.to_string()),
+        };
+        let response: LockdownResponse = self.request(&request).await?;
+        check_response(response.error)
+    }
+
+    /// Reads the device name, model and iOS version.
+    pub async fn device_info(&mut self) -> Result<DeviceInfo, LockdownError> {
+        self.get_value(None, None).await
+    }
+
+    /// Returns whether developer mode is enabled.
+    pub async fn developer_mode_status(&mut self) -> Result<bool, LockdownError> {
+        self.get_value(
+            Some("com.apple.security.mac.amfi"),
+            Some("DeveloperModeStatus"),
+        )
+        .await
+    }
+
+    /// Returns whether the device accepts lockdown connections over Wi-Fi.
+    pub async fn wifi_connections(&mut self) -> Result<bool, LockdownError> {
+        self.get_value(
+            Some(WIRELESS_LOCKDOWN_DOMAIN),
+            Some("EnableWifiConnections"),
+        )
+        .await
+    }
+
+    /// Lets paired hosts reach the device over Wi-Fi, which makes usbmuxd
+    /// list it as a network device. Needs a session.
+    pub async fn set_wifi_connections(&mut self, enabled: bool) -> Result<(), LockdownError> {
+        self.set_value(
+            Some(WIRELESS_LOCKDOWN_DOMAIN),
+            "EnableWifiConnections",
+            enabled,
+        )
+        .await
+    }
+
+    /// Starts a session with the host identity of the usbmux client's pair
+    /// record, switching the connection to TLS only when lockdownd asks for
+    /// it with `EnableSessionSSL`.
+    ///
+    /// # Errors
+    ///
+    /// Returns [`LockdownError::InvalidHostID`] if the device no longer knows
+    /// the pair record, in which case it has to be paired again.
+    pub async fn start_session(&mut self) -> Result<(), LockdownError> {
+        let request = LockdownRequest::StartSession {
+            host_id: self
+                .usbmux
+                .host_id
+                .clone()
+                .ok_or(LockdownError::MissingArguments("HostID"))?
+                .to_uppercase(),
+            system_buid: self
+                .usbmux
+                .system_buid
+                .clone()
+                .ok_or(LockdownError::MissingArguments("SystemBUID"))?,
+        };
+        let response: StartSessionResponse = self.request(&request).await?;
+        check_response(response.error)?;
+        match response.request.as_deref() {
+            Some("StartSession") | None => {}
+            Some(other) => {
+                return Err(LockdownError::UnexpectedResponse {
+                    expected: "StartSession",
+                    received: other.to_string(),
+                })
+            }
+        }
+        let session_id = response
+            .session_id
+            .ok_or_else(|| LockdownError::MissingValue("SessionID".to_string()))?;
+
+        self.session_id = Some(session_id);
+        if response.enable_session_ssl {
+            self.usbmux.try_ssl_handshake().await?;
+        }
+        self.session_ssl = response.enable_session_ssl;
+        Ok(())
+    }
+
+    /// Stops the running session.
+    pub async fn stop_session(&mut self) -> Result<(), LockdownError> {
+        let session_id = self
+            .session_id
+            .take()
+            .ok_or(LockdownError::SessionInactive)?;
+        let response: LockdownResponse = self
+            .request(&LockdownRequest::StopSession { session_id })
+            .await?;
+        check_response(response.error)
+    }
+
+    /// Starts `name` and connects to it, with TLS if lockdownd asks for it
+    /// with `EnableServiceSSL`. Services guarded by data protection need the
+    /// pair record's `escrow_bag` while the device is locked.
+    ///
+    /// # Errors
+    ///
+    /// Returns [`LockdownError::InvalidService`] if the device does not offer
+    /// the service and [`LockdownError::PasswordProtected`] if it has to be
+    /// unlocked first.
+    pub async fn start_service(
+        &mut self,
+        name: &str,
+        escrow_bag: Option<Data>,
+    ) -> Result<ServiceConnection, LockdownError> {
+        self.start_service_with(name, escrow_bag, SslMode::Full)
+            .await
+    }
+
+    /// Like [`LockdownClient::start_service`], with TLS handled as `ssl_mode`
+    /// says when lockdownd enables it.
+    pub async fn start_service_with(
+        &mut self,
+        name: &str,
+        escrow_bag: Option<Data>,
+        ssl_mode: SslMode,
+    ) -> Result<ServiceConnection, LockdownError> {
+        let (port, ssl) = self.request_service(name, escrow_bag).await?;
+        let usbmux = self.usbmux.reconnect().await?;
+        ServiceConnection::connect(usbmux, port, ssl, ssl_mode).await
+    }
+
+    /// Asks lockdownd to start `name` and returns its port and whether it
+    /// expects TLS.
+    async fn request_service(
+        &mut self,
+        name: &str,
+        escrow_bag: Option<Data>,
+    ) -> Result<(u16, bool), LockdownError> {
+        let request = LockdownRequest::StartService {
+            service: name.to_string(),
+            escrow_bag,
+        };
+        let response: StartServiceResponse = self.request(&request).await?;
+        match response.error.as_deref() {
+            Some("InvalidService") => return Err(LockdownError::InvalidService(name.to_string())),
+            _ => check_response(response.error)?,
+        }
+        let port = response
+            .port
+            .ok_or_else(|| LockdownError::MissingValue("Port".to_string()))?;
+        Ok((port, response.enable_service_ssl.unwrap_or(false)))
+    }
+
+    /// Checks that the device still trusts `record`.
+    ///
+    /// # Errors
+    ///
+    /// Returns [`LockdownError::InvalidHostID`] if the device does not know
+    /// the record's host.
+    pub async fn validate_pair(&mut self, record: &PairRecord) -> Result<(), LockdownError> {
+        let request = LockdownRequest::ValidatePair {
+            pair_record: pair_request_record(record),
+            protocol_version: PAIRING_PROTOCOL_VERSION.to_string(),
+        };
+        let response: LockdownResponse = self.request(&request).await?;
+        check_response(response.error)
+    }
+
+    /// Makes the device forget the host of `record`. The record is useless
+    /// afterwards and should be deleted.
+    pub async fn unpair(&mut self, record: &PairRecord) -> Result<(), LockdownError> {
+        let request = LockdownRequest::Unpair {
+            pair_record: Dictionary::from_iter([(
+                "HostID".to_string(),
+                Value::from(record.host_id.clone()),
+            )]),
+            protocol_version: PAIRING_PROTOCOL_VERSION.to_string(),
+        };
+        let response: LockdownResponse = self.request(&request).await?;
+        check_response(response.error)
+    }
+
+    /// Pairs with the device, generating a new host identity.
+    ///
+    /// The user has to tap "Trust" on the device; lockdownd is asked again
+    /// every second until they do or `timeout` runs out. The returned record
+    /// includes the escrow bag and is used for subsequent sessions, but is
+    /// not persisted.
+    pub async fn pair(
+        &mut self,
+        system_buid: String,
+        timeout: Duration,
+    ) -> Result<PairRecord, LockdownError> {
+        let device_public_key: Data = self.get_value(None, Some("DevicePublicKey")).await?;
+        let mut record = generate_pair_record(
+            device_public_key.as_ref(),
+            Uuid::new_v4().to_string().to_uppercase(),
+            system_buid,
+        )?;
+        record.wifi_mac_address = self.get_value(None, Some("WiFiAddress")).await.ok();
+
+        let request = LockdownRequest::Pair {
+            pair_record: pair_request_record(&record),
+            protocol_version: PAIRING_PROTOCOL_VERSION.to_string(),
+            pairing_options: Some(Dictionary::from_iter([(
+                "ExtendedPairingErrors".to_string(),
+                Value::Boolean(true),
+            )])),
+        };
+        let deadline = Instant::now() + timeout;
+        loop {
+            let response: PairResponse = self.request(&request).await?;
+            match check_response(response.error) {
+                Ok(()) => {
+                    record.escrow_bag = response.escrow_bag;
+                    break;
+                }
+                Err(LockdownError::PairingDialogResponsePending) if Instant::now() < deadline => {
+                    tokio::time::sleep(Duration::from_secs(1)).await
+                }
+                Err(error) => return Err(error),
+            }
+        }
+
+        self.usbmux.use_pair_record(&record);
+        Ok(record)
+    }
+}
+
+/// Turns the `Error` of a lockdownd reply into a result.
+fn check_response(error: Option<String>) -> Result<(), LockdownError> {
+    match error {
+        Some(error) => Err(LockdownError::from_response(error)),
+        None => Ok(()),
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::time::Duration;
+
+    use plist::{Data, Dictionary, Value};
+    use tokio::io::{AsyncReadExt, AsyncWriteExt, DuplexStream};
+
+    use super::{errors::LockdownError, LockdownClient};
+    use crate::usbmux::pair_record::PairRecord;
+    use crate::usbmux::UsbMuxClient;
+
+    async fn read_request(server: &mut DuplexStream) -> Dictionary {
+        let length = server.read_u32().await.unwrap();
+        let mut payload = vec![0u8; length as usize];
+        server.read_exact(&mut payload).await.unwrap();
+        plist::from_bytes(&payload).unwrap()
+    }
+
+    async fn write_response(server: &mut DuplexStream, response: Dictionary) {
+        let mut bytes = Vec::new();
+        plist::to_writer_xml(&mut bytes, &response).unwrap();
+        server.write_u32(bytes.len() as u32).await.unwrap();
+        server.write_all(&bytes).await.unwrap();
+    }
+
+    fn client(duplex: DuplexStream) -> LockdownClient {
+        LockdownClient::from_client(UsbMuxClient::from_transport(Box::new(duplex)))
+    }
+
+    #[tokio::test]
+    async fn test_values() {
+        let (client_end, mut server) = tokio::io::duplex(4096);
+        let mut lockdown = client(client_end);
+
+        let device = tokio::spawn(async move {
+            let request = read_request(&mut server).await;
+            assert_eq!(
+                request.get("Request").and_then(|v| v.as_string()),
+                Some("GetValue")
+            );
+            assert_eq!(
+                request.get("Key").and_then(|v| v.as_string()),
+                Some("ProductVersion")
+            );
+            assert!(!request.contains_key("Domain"));
+            let mut response = Dictionary::new();
+            response.insert("Value".to_string(), Value::from("17.4.1"));
+            write_response(&mut server, response).await;
+
+            let request = read_request(&mut server).await;
+            assert_eq!(
+                request.get("Value").and_then(|v| v.as_boolean()),
+                Some(true)
+            );
+            let mut response = Dictionary::new();
+            response.insert("Error".to_string(), Value::from("MissingValue"));
+            write_response(&mut server, response).await;
+        });
+
+        let version: String = lockdown
+            .get_value(None, Some("ProductVersion"))
+            .await
+            .unwrap();
+        assert_eq!(version, "17.4.1");
+
+        let result = lockdown
+            .set_value(
+                Some("com.apple.mobile.wireless_lockdown"),
+                "EnableWifiConnections",
+                true,
+            )
+            .await;
+        device.await.unwrap();
+        assert!(matches!(result, Err(LockdownError::Error(error)) if error == "MissingValue"));
+    }
+
+    #[tokio::test]
+    async fn test_pair_denied() {
+        let (client_end, mut server) = tokio::io::duplex(16384);
+        let mut lockdown = client(client_end);
+
+        let device = tokio::spawn(async move {
+            let device_key =
+                rcgen::KeyPair::generate_rsa_for(&rcgen::PKCS_RSA_SHA256, rcgen::RsaKeySize::_2048)
+                    .unwrap();
+            let device_pem = pem::encode(&pem::Pem::new(
+                "RSA PUBLIC KEY",
+                device_key.public_key_raw().to_vec(),
+            ));
+            let mut response = Dictionary::new();
+            response.insert("Value".to_string(), Value::Data(device_pem.into_bytes()));
+            read_request(&mut server).await;
+            write_response(&mut server, response).await;
+
+            let mut response = Dictionary::new();
+            response.insert("Error".to_string(), Value::from("MissingValue"));
+            read_request(&mut server).await;
+            write_response(&mut server, response).await;
+
+            let mut requests = Vec::new();
+            for error in ["PairingDialogResponsePending", "UserDeniedPairing"] {
+                requests.push(read_request(&mut server).await);
+                let mut response = Dictionary::new();
+                response.insert("Error".to_string(), Value::from(error));
+                write_response(&mut server, response).await;
+            }
+            requests
+        });
+
+        let result = lockdown
+            .pair("BUID".to_string(), Duration::from_secs(30))
+            .await;
+        let requests = device.await.unwrap();
+        assert!(matches!(result, Err(LockdownError::UserDeniedPairing)));
+
+        let pair_record = requests[0]
+            .get("PairRecord")
+            .and_then(|v| v.as_dictionary())
+            .unwrap();
+        assert_eq!(
+            pair_record.get("SystemBUID").and_then(|v| v.as_string()),
+            Some("BUID")
+        );
+        assert!(pair_record.contains_key("DeviceCertificate"));
+        assert!(!pair_record.contains_key("HostPrivateKey"));
+    }
+
+    fn paired_client(duplex: DuplexStream) -> LockdownClient {
+        let mut lockdown = client(duplex);
+        lockdown.usbmux.host_id = Some("host".to_string());
+        lockdown.usbmux.system_buid = Some("BUID".to_string());
+        lockdown
+    }
+
+    #[tokio::test]
+    async fn test_start_session() {
+        let (client_end, mut server) = tokio::io::duplex(4096);
+        let mut lockdown = paired_client(client_end);
+
+        let device = tokio::spawn(async move {
+            let request = read_request(&mut server).await;
+            assert_eq!(
+                request.get("HostID").and_then(|v| v.as_string()),
+                Some("HOST")
+            );
+            let mut response = Dictionary::new();
+            response.insert("Request".to_string(), Value::from("StartSession"));
+            response.insert("SessionID".to_string(), Value::from("5E55"));
+            response.insert("EnableSessionSSL".to_string(), Value::Boolean(false));
+            write_response(&mut server, response).await;
+
+            let request = read_request(&mut server).await;
+            assert_eq!(
+                request.get("SessionID").and_then(|v| v.as_string()),
+                Some("5E55")
+            );
+            let mut response = Dictionary::new();
+            response.insert("Request".to_string(), Value::from("StopSession"));
+            write_response(&mut server, response).await;
+        });
+
+        lockdown.start_session().await.unwrap();
+        assert_eq!(lockdown.session_id.as_deref(), Some("5E55"));
+        assert!(!lockdown.session_ssl);
+        lockdown.stop_session().await.unwrap();
+        device.await.unwrap();
+        assert!(matches!(
+            lockdown.stop_session().await,
+            Err(LockdownError::SessionInactive)
+        ));
+    }
+
+    #[tokio::test]
+    async fn test_request_service() {
+        let (client_end, mut server) = tokio::io::duplex(4096);
+        let mut lockdown = paired_client(client_end);
+
+        let device = tokio::spawn(async move {
+            let request = read_request(&mut server).await;
+            assert_eq!(
+                request.get("Service").and_then(|v| v.as_string()),
+                Some("com.apple.amfi.lockdown")
+            );
+            assert!(request.contains_key("EscrowBag"));
+            let mut response = Dictionary::new();
+            response.insert("Port".to_string(), Value::from(49152u64));
+            response.insert("EnableServiceSSL".to_string(), Value::Boolean(true));
+            write_response(&mut server, response).await;
+
+            for error in ["InvalidService", "PasswordProtected"] {
+                read_request(&mut server).await;
+                let mut response = Dictionary::new();
+                response.insert("Error".to_string(), Value::from(error));
+                write_response(&mut server, response).await;
+            }
+        });
+
+        let escrow_bag = Some(Data::new(vec![1, 2, 3]));
+        assert_eq!(
+            lockdown
+                .request_service("com.apple.amfi.lockdown", escrow_bag)
+                .await
+                .unwrap(),
+            (49152, true)
+        );
+        assert!(matches!(
+            lockdown.request_service("com.apple.nope", None).await,
+            Err(LockdownError::InvalidService(name)) if name == "com.apple.nope"
+        ));
+        assert!(matches!(
+            lockdown.request_service("com.apple.afc", None).await,
+            Err(LockdownError::PasswordProtected)
+        ));
+        device.await.unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_validate_and_unpair() {
+        let (client_end, mut server) = tokio::io::duplex(16384);
+        let mut lockdown = client(client_end);
+        let record = PairRecord {
+            host_id: "HOST".to_string(),
+            system_buid: "BUID".to_string(),
+            host_certificate: Data::new(b"host cert".to_vec()),
+            host_private_key: b"host key".to_vec().into(),
+            device_certificate: None,
+            root_certificate: None,
+            root_private_key: None,
+            escrow_bag: None,
+            wifi_mac_address: None,
+        };
+
+        let device = tokio::spawn(async move {
+            let mut requests = Vec::new();
+            for error in [Some("InvalidHostID"), None] {
+                requests.push(read_request(&mut server).await);
+                let mut response = Dictionary::new();
+                if let Some(error) = error {
+                    response.insert("Error".to_string(), Value::from(error));
+                }
+                write_response(&mut server, response).await;
+            }
+            requests
+        });
+
+        assert!(matches!(
+            lockdown.validate_pair(&record).await,
+            Err(LockdownError::InvalidHostID)
+        ));
+        lockdown.unpair(&record).await.unwrap();
+        let requests = device.await.unwrap();
+
+        assert_eq!(
+            requests[0].get("Request").and_then(|v| v.as_string()),
+            Some("ValidatePair")
+        );
+        let sent = requests[0]
+            .get("PairRecord")
+            .and_then(|v| v.as_dictionary())
+            .unwrap();
+        assert!(sent.contains_key("HostCertificate"));
+        assert!(!sent.contains_key("HostPrivateKey"));
+
+        assert_eq!(
+            requests[1].get("Request").and_then(|v| v.as_string()),
+            Some("Unpair")
+        );
+        let sent = requests[1]
+            .get("PairRecord")
+            .and_then(|v| v.as_dictionary())
+            .unwrap();
+        assert_eq!(sent.len(), 1);
+        assert_eq!(sent.get("HostID").and_then(|v| v.as_string()), Some("HOST"));
+    }
+
+    #[tokio::test]
+    async fn test_start_session_errors() {
+        let (client_end, mut server) = tokio::io::duplex(4096);
+        let mut lockdown = paired_client(client_end);
+
+        let device = tokio::spawn(async move {
+            read_request(&mut server).await;
+            let mut response = Dictionary::new();
+            response.insert("Request".to_string(), Value::from("StartSession"));
+            response.insert("Error".to_string(), Value::from("InvalidHostID"));
+            write_response(&mut server, response).await;
+
+            read_request(&mut server).await;
+            let mut response = Dictionary::new();
+            response.insert("Request".to_string(), Value::from("StartSession"));
+            write_response(&mut server, response).await;
+        });
+
+        assert!(matches!(
+            lockdown.start_session().await,
+            Err(LockdownError::InvalidHostID)
+        ));
+        assert!(matches!(
+            lockdown.start_session().await,
+            Err(LockdownError::MissingValue(key)) if key == "SessionID"
+        ));
+        device.await.unwrap();
+    }
+}
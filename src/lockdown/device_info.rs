@@ -0,0 +1,58 @@
+use serde::Deserialize;
+
+/// Identity and software details of a device, as reported by lockdownd's
+/// global domain. Values lockdownd may withhold from hosts without a session
+/// are optional.
+#[derive(Debug, Clone, Deserialize)]
+#[serde(rename_all = "PascalCase")]
+pub struct DeviceInfo {
+    pub device_name: Option<String>,
+    /// Model identifier, e.g. `iPhone14,2`.
+    pub product_type: String,
+    /// iOS version, e.g. `17.4.1`.
+    pub product_version: String,
+    pub build_version: Option<String>,
+    #[serde(rename = "UniqueDeviceID")]
+    pub unique_device_id: String,
+    /// Board identifier, e.g. `D63AP`.
+    pub hardware_model: Option<String>,
+    /// `Activated`, `Unactivated`, ...
+    pub activation_state: Option<String>,
+}
+
+#[cfg(test)]
+mod test {
+    use plist::{Dictionary, Value};
+
+    use super::DeviceInfo;
+
+    #[test]
+    fn test_parse_device_info() {
+        let mut values = Dictionary::new();
+        values.insert("DeviceName".to_string(), Value::from("iPhone"));
+        values.insert("ProductType".to_string(), Value::from("iPhone14,2"));
+        values.insert("ProductVersion".to_string(), Value::from("17.4.1"));
+        values.insert("BuildVersion".to_string(), Value::from("21E236"));
+        values.insert("UniqueDeviceID".to_string(), Value::from("00008030-001A"));
+        values.insert("ActivationState".to_string(), Value::from("Activated"));
+        values.insert("CPUArchitecture".to_string(), Value::from("arm64e"));
+
+        let info: DeviceInfo = plist::from_value(&Value::Dictionary(values)).unwrap();
+        assert_eq!(info.product_version, "17.4.1");
+        assert_eq!(info.unique_device_id, "00008030-001A");
+        assert_eq!(info.hardware_model, None);
+        assert_eq!(info.activation_state.as_deref(), Some("Activated"));
+    }
+
+    #[test]
+    fn test_parse_withheld_values() {
+        let mut values = Dictionary::new();
+        values.insert("ProductType".to_string(), Value::from("iPhone14,2"));
+        values.insert("ProductVersion".to_string(), Value::from("17.4.1"));
+        values.insert("UniqueDeviceID".to_string(), Value::from("00008030-001A"));
+
+        let info: DeviceInfo = plist::from_value(&Value::Dictionary(values)).unwrap();
+        assert_eq!(info.device_name, None);
+        assert_eq!(info.build_version, None);
+    }
+}
@@ -0,0 +1,50 @@
+use crate::usbmux::errors::{MessageOperationError, UsbmuxOperationError};
+
+#[derive(Debug, thiserror::Error)]
+pub enum LockdownError {
+    #[error("UsbmuxOperation error: {0}")]
+    UsbmuxOperationError(#[from] UsbmuxOperationError),
+    #[error("MessageOperation error: {0}")]
+    MessageOperationError(#[from] MessageOperationError),
+    #[error("Plist error: {0}")]
+    Plist(#[from] plist::Error),
+    #[error("Certificate error: {0}")]
+    CertificateError(#[from] rcgen::Error),
+    #[error("Missing arguments: {0}")]
+    MissingArguments(&'static str),
+    #[error("Missing value: {0}")]
+    MissingValue(String),
+    #[error("Waiting for the user to trust this computer")]
+    PairingDialogResponsePending,
+    #[error("The user did not trust this computer")]
+    UserDeniedPairing,
+    #[error("Device is locked, unlock it and try again")]
+    PasswordProtected,
+    #[error("The device no longer accepts this pair record, run `pair` again")]
+    InvalidHostID,
+    #[error("lockdownd does not offer service {0}")]
+    InvalidService(String),
+    #[error("No lockdown session is running")]
+    SessionInactive,
+    #[error("Unexpected reply to {expected}: {received}")]
+    UnexpectedResponse {
+        expected: &'static str,
+        received: String,
+    },
+    #[error("Lockdown error: {0}")]
+    Error(String),
+}
+
+impl LockdownError {
+    /// Maps the `Error` string of a lockdownd reply to an error.
+    pub fn from_response(error: String) -> Self {
+        match error.as_str() {
+            "PairingDialogResponsePending" => LockdownError::PairingDialogResponsePending,
+            "UserDeniedPairing" => LockdownError::UserDeniedPairing,
+            "PasswordProtected" => LockdownError::PasswordProtected,
+            "InvalidHostID" => LockdownError::InvalidHostID,
+            "SessionInactive" | "NoRunningSession" => LockdownError::SessionInactive,
+            _ => LockdownError::Error(error),
+        }
+    }
+}
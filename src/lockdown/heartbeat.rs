@@ -0,0 +1,127 @@
+use std::time::Duration;
+
+use log::debug;
+
+use super::errors::LockdownError;
+use super::service::{ServiceConnection, HEARTBEAT_SERVICE};
+use super::LockdownClient;
+use crate::usbmux::message::{HeartbeatMessage, HeartbeatReply};
+
+/// Interval assumed until the device announces one.
+const DEFAULT_INTERVAL: Duration = Duration::from_secs(10);
+/// Extra time given to a late `Marco` before the link is considered dead.
+const GRACE: Duration = Duration::from_secs(5);
+
+/// Keeps a Wi-Fi connection to the device alive by answering the
+/// `com.apple.mobile.heartbeat` service. The device drops network
+/// connections from hosts that stop answering.
+pub struct HeartbeatClient {
+    service: ServiceConnection,
+    /// Interval the device last announced.
+    pub interval: Duration,
+}
+
+impl HeartbeatClient {
+    /// Starts the heartbeat service over `lockdown`, which needs a session.
+    pub async fn connect(lockdown: &mut LockdownClient) -> Result<Self, LockdownError> {
+        let service = lockdown.start_service(HEARTBEAT_SERVICE, None).await?;
+        Ok(HeartbeatClient::new(service))
+    }
+
+    /// Creates a client over a connection to the heartbeat service.
+    pub fn new(service: ServiceConnection) -> Self {
+        HeartbeatClient {
+            service,
+            interval: DEFAULT_INTERVAL,
+        }
+    }
+
+    /// Waits for the next `Marco` and answers it with `Polo`. Other commands,
+    /// such as the `SleepyTime` sent before the device sleeps, are skipped,
+    /// and the device may then take as long as it needs to send the next one.
+    pub async fn poll(&mut self) -> Result<(), LockdownError> {
+        let mut timeout = Some(self.interval + GRACE);
+        loop {
+            let message: HeartbeatMessage = match timeout {
+                Some(timeout) => tokio::time::timeout(timeout, self.service.read_plist())
+                    .await
+                    .map_err(|_| LockdownError::Error("Heartbeat timed out".to_string()))??,
+                None => self.service.read_plist().await?,
+            };
+            if let Some(interval) = message.interval {
+                self.interval = Duration::from_secs(interval);
+            }
+            if message.command == "Marco" {
+                break;
+            }
+            debug!("Skipping heartbeat command {}", message.command);
+            timeout = None;
+        }
+        self.service
+            .send_plist(&HeartbeatReply { command: "Polo" })
+            .await
+    }
+
+    /// Answers heartbeats until the connection fails.
+    pub async fn run(mut self) -> Result<(), LockdownError> {
+        loop {
+            self.poll().await?;
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::time::Duration;
+
+    use plist::{Dictionary, Value};
+    use tokio::io::{AsyncReadExt, AsyncWriteExt};
+
+    use super::HeartbeatClient;
+    use crate::lockdown::service::ServiceConnection;
+    use crate::usbmux::UsbMuxClient;
+
+    #[tokio::test]
+    async fn test_heartbeat() {
+        let (client_end, mut server) = tokio::io::duplex(4096);
+        let mut heartbeat = HeartbeatClient::new(ServiceConnection {
+            usbmux: UsbMuxClient::from_transport(Box::new(client_end)),
+            port: 0,
+            ssl: false,
+        });
+
+        let device = tokio::spawn(async move {
+            let mut replies = Vec::new();
+            for command in ["Marco", "SleepyTime", "Marco"] {
+                let mut message = Dictionary::new();
+                message.insert("Command".to_string(), Value::from(command));
+                message.insert("Interval".to_string(), Value::from(15u64));
+                let mut bytes = Vec::new();
+                plist::to_writer_xml(&mut bytes, &message).unwrap();
+                server.write_u32(bytes.len() as u32).await.unwrap();
+                server.write_all(&bytes).await.unwrap();
+
+                if command == "Marco" {
+                    let length = server.read_u32().await.unwrap();
+                    let mut payload = vec![0u8; length as usize];
+                    server.read_exact(&mut payload).await.unwrap();
+                    replies.push(plist::from_bytes::<Dictionary>(&payload).unwrap());
+                }
+            }
+            replies
+        });
+
+        heartbeat.poll().await.unwrap();
+        assert_eq!(heartbeat.interval, Duration::from_secs(15));
+        heartbeat.poll().await.unwrap();
+
+        let replies = device.await.unwrap();
+        assert_eq!(replies.len(), 2);
+        for reply in replies {
+            assert_eq!(
+                reply.get("Command").and_then(|v| v.as_string()),
+                Some("Polo")
+            );
+        }
+    }
+}
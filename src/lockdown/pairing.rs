@@ -0,0 +1,149 @@
+use plist::{Data, Dictionary, Value};
+use rcgen::{
+    BasicConstraints, CertificateParams, DistinguishedName, IsCa, KeyPair, KeyUsagePurpose,
+    PublicKeyData, RsaKeySize, SignatureAlgorithm, PKCS_RSA_SHA256,
+};
+use time::{Duration, OffsetDateTime};
+
+use super::errors::LockdownError;
+use crate::usbmux::pair_record::PairRecord;
+
+/// Pairing protocol version sent in `Pair` requests.
+pub const PAIRING_PROTOCOL_VERSION: &str = "2";
+
+/// Validity of the generated certificates, in days.
+const CERTIFICATE_VALIDITY_DAYS: i64 = 365 * 10;
+
+/// The device's RSA public key as returned by lockdownd's `DevicePublicKey`,
+/// a PEM encoded PKCS#1 `RSA PUBLIC KEY`.
+struct DevicePublicKey {
+    der: Vec<u8>,
+}
+
+impl PublicKeyData for DevicePublicKey {
+    fn der_bytes(&self) -> &[u8] {
+        &self.der
+    }
+
+    fn algorithm(&self) -> &SignatureAlgorithm {
+        &PKCS_RSA_SHA256
+    }
+}
+
+fn certificate_params(is_ca: bool) -> CertificateParams {
+    let mut params = CertificateParams::default();
+    params.distinguished_name = DistinguishedName::new();
+    params.not_before = OffsetDateTime::now_utc();
+    params.not_after = params.not_before + Duration::days(CERTIFICATE_VALIDITY_DAYS);
+    if is_ca {
+        params.is_ca = IsCa::Ca(BasicConstraints::Unconstrained);
+    } else {
+        params.is_ca = IsCa::ExplicitNoCa;
+        params.key_usages = vec![
+            KeyUsagePurpose::DigitalSignature,
+            KeyUsagePurpose::KeyEncipherment,
+        ];
+    }
+    params
+}
+
+/// Generates a root CA, a host identity and a device certificate for the
+/// device owning `device_public_key`, the way iTunes does before pairing.
+///
+/// The returned record has no escrow bag yet; lockdownd hands it out in its
+/// reply to `Pair`.
+pub fn generate_pair_record(
+    device_public_key: &[u8],
+    host_id: String,
+    system_buid: String,
+) -> Result<PairRecord, LockdownError> {
+    let device_key = pem::parse(device_public_key)
+        .map_err(|_| LockdownError::Error("Invalid DevicePublicKey".to_string()))?;
+    let device_key = DevicePublicKey {
+        der: device_key.into_contents(),
+    };
+
+    let root_key = KeyPair::generate_rsa_for(&PKCS_RSA_SHA256, RsaKeySize::_2048)?;
+    let root_cert = certificate_params(true).self_signed(&root_key)?;
+
+    let host_key = KeyPair::generate_rsa_for(&PKCS_RSA_SHA256, RsaKeySize::_2048)?;
+    let host_cert = certificate_params(false).signed_by(&host_key, &root_cert, &root_key)?;
+
+    let device_cert = certificate_params(false).signed_by(&device_key, &root_cert, &root_key)?;
+
+    Ok(PairRecord {
+        host_id,
+        system_buid,
+        host_certificate: Data::new(host_cert.pem().into_bytes()),
+        host_private_key: host_key.serialize_pem().into_bytes().into(),
+        device_certificate: Some(Data::new(device_cert.pem().into_bytes())),
+        root_certificate: Some(Data::new(root_cert.pem().into_bytes())),
+        root_private_key: Some(root_key.serialize_pem().into_bytes().into()),
+        escrow_bag: None,
+        wifi_mac_address: None,
+    })
+}
+
+/// The `PairRecord` sent to lockdownd: the certificates and identifiers of
+/// `record`, without any private key.
+pub fn pair_request_record(record: &PairRecord) -> Dictionary {
+    let mut dict = Dictionary::new();
+    if let Some(cert) = &record.device_certificate {
+        dict.insert(
+            "DeviceCertificate".to_string(),
+            Value::Data(cert.clone().into()),
+        );
+    }
+    dict.insert(
+        "HostCertificate".to_string(),
+        Value::Data(record.host_certificate.clone().into()),
+    );
+    dict.insert("HostID".to_string(), Value::from(record.host_id.clone()));
+    if let Some(cert) = &record.root_certificate {
+        dict.insert(
+            "RootCertificate".to_string(),
+            Value::Data(cert.clone().into()),
+        );
+    }
+    dict.insert(
+        "SystemBUID".to_string(),
+        Value::from(record.system_buid.clone()),
+    );
+    if let Some(mac) = &record.wifi_mac_address {
+        dict.insert("WiFiMACAddress".to_string(), Value::from(mac.clone()));
+    }
+    dict
+}
+
+#[cfg(test)]
+mod test {
+    use rcgen::{KeyPair, RsaKeySize, PKCS_RSA_SHA256};
+
+    use super::{generate_pair_record, pair_request_record};
+
+    #[test]
+    fn test_generate_pair_record() {
+        // Stand-in for the device key: the PKCS#1 public key of a fresh pair.
+        let device_key = KeyPair::generate_rsa_for(&PKCS_RSA_SHA256, RsaKeySize::_2048).unwrap();
+        let device_pem = pem::encode(&pem::Pem::new(
+            "RSA PUBLIC KEY",
+            device_key.public_key_raw().to_vec(),
+        ));
+
+        let record = generate_pair_record(
+            device_pem.as_bytes(),
+            "HOST".to_string(),
+            "BUID".to_string(),
+        )
+        .unwrap();
+        let host_cert = String::from_utf8(record.host_certificate.clone().into()).unwrap();
+        assert!(host_cert.starts_with("-----BEGIN CERTIFICATE-----"));
+        assert!(record.root_private_key.is_some());
+
+        let request = pair_request_record(&record);
+        assert!(request.contains_key("DeviceCertificate"));
+        assert!(request.contains_key("RootCertificate"));
+        assert!(!request.contains_key("HostPrivateKey"));
+        assert!(!request.contains_key("RootPrivateKey"));
+    }
+}
@@ -0,0 +1,241 @@
+use serde::{de::DeserializeOwned, Serialize};
+
+use super::errors::LockdownError;
+use crate::transport::BoxedTransport;
+use crate::usbmux::UsbMuxClient;
+
+/// Tunnels the device's network stack to the host, for RSD and developer
+/// services on iOS 17+.
+pub const CORE_DEVICE_PROXY_SERVICE: &str = "com.apple.internal.devicecompute.CoreDeviceProxy";
+/// Reveals and enables developer mode.
+pub const AMFI_SERVICE: &str = "com.apple.amfi.lockdown";
+/// Keepalive for devices connected over Wi-Fi.
+pub const HEARTBEAT_SERVICE: &str = "com.apple.mobile.heartbeat";
+
+/// What a service connection does with TLS when lockdownd enables it.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum SslMode {
+    /// Everything after the handshake is encrypted.
+    #[default]
+    Full,
+    /// TLS only authenticates the host; the service then talks plaintext on
+    /// the same connection. Used by some legacy services.
+    HandshakeOnly,
+}
+
+/// A connection to a service started through lockdownd, ready for the
+/// service's own protocol.
+pub struct ServiceConnection {
+    /// The connection, already switched to the service and TLS as needed.
+    pub usbmux: UsbMuxClient,
+    /// Device port the service listens on.
+    pub port: u16,
+    /// Whether lockdownd asked for TLS on this connection.
+    pub ssl: bool,
+}
+
+impl ServiceConnection {
+    /// Connects `usbmux`, which must have a device and host identity, to the
+    /// service on `port`, running a TLS handshake when `ssl` is set.
+    pub async fn connect(
+        mut usbmux: UsbMuxClient,
+        port: u16,
+        ssl: bool,
+        ssl_mode: SslMode,
+    ) -> Result<Self, LockdownError> {
+        usbmux.connect_to_service(port).await?;
+        if ssl {
+            match ssl_mode {
+                SslMode::Full => usbmux.try_ssl_handshake().await?,
+                SslMode::HandshakeOnly => usbmux.ssl_handshake_only().await?,
+            }
+        }
+        Ok(ServiceConnection { usbmux, port, ssl })
+    }
+
+    /// Sends a length-prefixed plist message.
+    pub async fn send_plist<T: Serialize>(&mut self, message: &T) -> Result<(), LockdownError> {
+        Ok(self.usbmux.send_plist_message(message).await?)
+    }
+
+    /// Reads a length-prefixed plist message.
+    pub async fn read_plist<T: DeserializeOwned>(&mut self) -> Result<T, LockdownError> {
+        let value = self.usbmux.read_plist_message().await?;
+        Ok(plist::from_value(&value)?)
+    }
+
+    /// Returns the raw connection, for services with their own framing.
+    pub fn into_transport(mut self) -> Result<BoxedTransport, LockdownError> {
+        self.usbmux
+            .sock
+            .take()
+            .ok_or_else(|| LockdownError::Error("missing service socket".to_string()))
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::sync::Arc;
+
+    use plist::{Dictionary, Value};
+    use rcgen::{CertificateParams, KeyPair};
+    use rustls::pki_types::{CertificateDer, PrivateKeyDer};
+    use rustls::ServerConfig;
+    use rustls_pki_types::pem::PemObject;
+    use tokio::io::{AsyncReadExt, AsyncWriteExt, DuplexStream};
+    use tokio_rustls::TlsAcceptor;
+
+    use super::{ServiceConnection, SslMode};
+    use crate::usbmux::message::AmfiRequest;
+    use crate::usbmux::UsbMuxClient;
+
+    /// Returns a self-signed certificate and key, both PEM encoded.
+    fn identity() -> (String, String) {
+        let key = KeyPair::generate().unwrap();
+        let cert = CertificateParams::new(vec![])
+            .unwrap()
+            .self_signed(&key)
+            .unwrap();
+        (cert.pem(), key.serialize_pem())
+    }
+
+    /// Reads a usbmuxd `Connect` request and accepts it.
+    async fn accept_connect(server: &mut DuplexStream) {
+        let total_length = server.read_u32_le().await.unwrap();
+        let mut payload = vec![0u8; total_length as usize - 4];
+        server.read_exact(&mut payload).await.unwrap();
+
+        let mut result = Dictionary::new();
+        result.insert("MessageType".to_string(), Value::from("Result"));
+        result.insert("Number".to_string(), Value::from(0u64));
+        let mut bytes = Vec::new();
+        plist::to_writer_xml(&mut bytes, &result).unwrap();
+        server.write_u32_le(bytes.len() as u32 + 16).await.unwrap();
+        server.write_u32_le(1).await.unwrap();
+        server.write_u32_le(8).await.unwrap();
+        server.write_u32_le(1).await.unwrap();
+        server.write_all(&bytes).await.unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_connect_plain() {
+        let (client_end, mut server) = tokio::io::duplex(4096);
+        let mut usbmux = UsbMuxClient::from_transport(Box::new(client_end));
+        usbmux.device_id = Some(7);
+
+        let device = tokio::spawn(async move {
+            let total_length = server.read_u32_le().await.unwrap();
+            let mut payload = vec![0u8; total_length as usize - 4];
+            server.read_exact(&mut payload).await.unwrap();
+            let request = Value::from_reader_xml(&payload[12..]).unwrap();
+            let request = request.as_dictionary().unwrap();
+            assert_eq!(
+                request.get("MessageType").and_then(|v| v.as_string()),
+                Some("Connect")
+            );
+            assert_eq!(
+                request
+                    .get("PortNumber")
+                    .and_then(|v| v.as_unsigned_integer()),
+                Some(49152u16.to_be() as u64)
+            );
+
+            let mut result = Dictionary::new();
+            result.insert("MessageType".to_string(), Value::from("Result"));
+            result.insert("Number".to_string(), Value::from(0u64));
+            let mut bytes = Vec::new();
+            plist::to_writer_xml(&mut bytes, &result).unwrap();
+            server.write_u32_le(bytes.len() as u32 + 16).await.unwrap();
+            server.write_u32_le(1).await.unwrap();
+            server.write_u32_le(8).await.unwrap();
+            server.write_u32_le(1).await.unwrap();
+            server.write_all(&bytes).await.unwrap();
+
+            let length = server.read_u32().await.unwrap();
+            let mut payload = vec![0u8; length as usize];
+            server.read_exact(&mut payload).await.unwrap();
+            let request: Dictionary = plist::from_bytes(&payload).unwrap();
+            assert_eq!(
+                request.get("action").and_then(|v| v.as_unsigned_integer()),
+                Some(0)
+            );
+        });
+
+        let mut service = ServiceConnection::connect(usbmux, 49152, false, SslMode::Full)
+            .await
+            .unwrap();
+        assert_eq!(service.port, 49152);
+        assert!(!service.ssl);
+        service
+            .send_plist(&AmfiRequest { action: 0 })
+            .await
+            .unwrap();
+        device.await.unwrap();
+        assert!(service.into_transport().is_ok());
+    }
+
+    #[tokio::test]
+    async fn test_connect_handshake_only() {
+        let (host_cert, host_key) = identity();
+        let (device_cert, device_key) = identity();
+        let (client_end, mut server) = tokio::io::duplex(16384);
+        let mut usbmux = UsbMuxClient::from_transport(Box::new(client_end));
+        usbmux.device_id = Some(7);
+        usbmux.host_cert = Some(host_cert.into_bytes().into_boxed_slice());
+        usbmux.host_key = Some(host_key.into_bytes().into());
+        usbmux.device_cert = Some(device_cert.clone().into_bytes().into_boxed_slice());
+
+        let mut config = ServerConfig::builder_with_provider(Arc::new(
+            rustls::crypto::aws_lc_rs::default_provider(),
+        ))
+        .with_safe_default_protocol_versions()
+        .unwrap()
+        .with_no_client_auth()
+        .with_single_cert(
+            vec![CertificateDer::from_pem_slice(device_cert.as_bytes()).unwrap()],
+            PrivateKeyDer::from_pem_slice(device_key.as_bytes()).unwrap(),
+        )
+        .unwrap();
+        // Tickets would follow the handshake as TLS records.
+        config.send_tls13_tickets = 0;
+        let device = tokio::spawn(async move {
+            accept_connect(&mut server).await;
+            let tls = TlsAcceptor::from(Arc::new(config))
+                .accept(server)
+                .await
+                .unwrap();
+            let (mut server, _) = tls.into_inner();
+
+            let mut message = Dictionary::new();
+            message.insert("Status".to_string(), Value::from("Ready"));
+            let mut bytes = Vec::new();
+            plist::to_writer_xml(&mut bytes, &message).unwrap();
+            server.write_u32(bytes.len() as u32).await.unwrap();
+            server.write_all(&bytes).await.unwrap();
+
+            let length = server.read_u32().await.unwrap();
+            let mut payload = vec![0u8; length as usize];
+            server.read_exact(&mut payload).await.unwrap();
+            plist::from_bytes::<Dictionary>(&payload).unwrap()
+        });
+
+        let mut service = ServiceConnection::connect(usbmux, 49152, true, SslMode::HandshakeOnly)
+            .await
+            .unwrap();
+        assert!(service.ssl);
+        let message: Dictionary = service.read_plist().await.unwrap();
+        assert_eq!(
+            message.get("Status").and_then(|v| v.as_string()),
+            Some("Ready")
+        );
+        service
+            .send_plist(&AmfiRequest { action: 1 })
+            .await
+            .unwrap();
+        let request = device.await.unwrap();
+        assert_eq!(
+            request.get("action").and_then(|v| v.as_unsigned_integer()),
+            Some(1)
+        );
+    }
+}
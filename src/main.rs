@@ -1,12 +1,21 @@
 use std::{
     io::{BufRead, Write},
-    path::PathBuf,
-    sync::Arc,
+    net::{Ipv4Addr, SocketAddr},
+    sync::{atomic::Ordering, Arc},
 };
 
-use rusty_loc_sim::device::Device;
+use rusty_loc_sim::device::{
+    CheckStatus, ConnectionType, Device, DeviceConnection, PairRecordStore, Passphrase,
+    PlistFormat, TunnelMode,
+};
 use std::env;
 use tokio::sync::RwLock;
+use tokio_stream::StreamExt;
+
+/// Environment variable holding the pair record passphrase.
+const PASSPHRASE_ENV: &str = "RUSTY_LOC_SIM_PASSPHRASE";
+/// Port of the `socks` proxy when none is given.
+const DEFAULT_SOCKS_PORT: u16 = 1080;
 
 #[tokio::main]
 async fn main() {
@@ -26,15 +35,50 @@ async fn main() {
                                                                         
 rustymobiledevice CLI
 Commands:
-  connect                Connect to device
-  reveal-developer-mode  Reveals Ios developer mode
+  list-devices           List attached devices
+  connection [usb | network | any | direct <IP>]
+                         Choose how devices are reached
+  wifi-connections on | off [-udid <UDID>]
+                         Allow reaching the device over Wi-Fi
+  watch                  Print device attach/detach events
+  info [-udid <UDID>]    Show device name, model and iOS version
+  connect [-udid <UDID>] Connect to device
+  doctor [-udid <UDID>]  Check what connect needs, with hints
+  pair [-udid <UDID>]    Pair with device (tap "Trust" on the device)
+  unpair [-udid <UDID>]  Revoke trust and delete the pair record
+  validate-pair [-udid <UDID>]
+                         Check that the device trusts its pair record
+  export-pair-record <FILE> [-udid <UDID>]
+                         Save the pair record to a file
+  import-pair-record <FILE> [-udid <UDID>]
+                         Use a pair record exported on another host
+  reveal-developer-mode [-udid <UDID>]
+                         Reveals Ios developer mode
+  enable-developer-mode [-udid <UDID>]
+                         Turn developer mode on (restarts the device)
   simulate-location -lat <latitude> -lng <longitude>
                          Simulate device location
+  forward [<LOCAL_PORT> <DEVICE_PORT>]
+                         Relay a local port to the device, or list relays
+  usbmux-forward <LOCAL_PORT> <DEVICE_PORT> [-udid <UDID>]
+                         Relay a local port to the device over usbmuxd
+  socks [<LOCAL_PORT>]   SOCKS5 proxy into the tunnel network (port 1080)
+  unforward <LOCAL_PORT> Stop relaying a local port
+  pair-records [usbmuxd | system | <DIR>]
+                         Choose where pair records are read from
+  passphrase [clear]     Encrypt pair records in a <DIR> or system store
+  pin-device-cert [on | off]
+                         Require the paired device's certificate in TLS
+  tunnel-mode [interface | userspace]
+                         Use a network interface or an in-process stack
+  plist-format [xml | binary]
+                         Encoding of the plists sent to the device
   exit | quit            Exit the CLI
 "#
     );
     let stdin = std::io::stdin();
     let mut device = Device::new();
+    let mut passphrase = env::var(PASSPHRASE_ENV).ok().map(Passphrase::new);
 
     let termination_token = Arc::new(RwLock::new(false));
 
@@ -58,7 +102,61 @@ Commands:
         let command = parts.next().unwrap_or("");
 
         match command {
-            "connect" => match device.connect(wintun_path.clone()).await {
+            "list-devices" => match device.list_devices().await {
+                Ok(devices) => {
+                    if devices.is_empty() {
+                        println!("No devices attached")
+                    }
+                    for info in devices {
+                        println!(
+                            "{}\t{:?}\tDeviceID={}",
+                            info.serial_number, info.connection_type, info.device_id
+                        )
+                    }
+                }
+                Err(error) => {
+                    println!("{}", error)
+                }
+            },
+            "watch" => match device.events().await {
+                Ok(events) => {
+                    tokio::spawn(async move {
+                        tokio::pin!(events);
+                        while let Some(Ok(event)) = events.next().await {
+                            println!("{:?}", event)
+                        }
+                    });
+                }
+                Err(error) => {
+                    println!("{}", error)
+                }
+            },
+            "info" => match device.device_info(udid_arg(parts).as_deref()).await {
+                Ok(info) => {
+                    if let Some(name) = info.device_name {
+                        println!("DeviceName:      {}", name);
+                    }
+                    println!("UniqueDeviceID:  {}", info.unique_device_id);
+                    println!("ProductType:     {}", info.product_type);
+                    println!("ProductVersion:  {}", info.product_version);
+                    if let Some(build) = info.build_version {
+                        println!("BuildVersion:    {}", build);
+                    }
+                    if let Some(model) = info.hardware_model {
+                        println!("HardwareModel:   {}", model);
+                    }
+                    if let Some(state) = info.activation_state {
+                        println!("ActivationState: {}", state);
+                    }
+                }
+                Err(error) => {
+                    println!("{}", error)
+                }
+            },
+            "connect" => match device
+                .connect(wintun_path.clone(), udid_arg(parts).as_deref())
+                .await
+            {
                 Ok(handle_tuple) => {
                     let termination_token_clone = termination_token.clone();
                     tokio::spawn(async move {
@@ -97,9 +195,28 @@ Commands:
                     println!("Connected")
                 }
                 Err(error) => {
-                    println!("{:?}", error)
+                    println!("{}", error);
+                    println!("Run `doctor` to find out what is missing")
                 }
             },
+            "doctor" => {
+                let checks = device
+                    .doctor(&wintun_path, udid_arg(parts).as_deref())
+                    .await;
+                for check in checks {
+                    let status = match check.status {
+                        CheckStatus::Pass => "ok",
+                        CheckStatus::Fail => "FAIL",
+                        CheckStatus::Skipped => "skip",
+                    };
+                    println!("{:<20} {:<5} {}", check.name, status, check.detail);
+                    if let (CheckStatus::Fail | CheckStatus::Skipped, Some(hint)) =
+                        (check.status, check.hint)
+                    {
+                        println!("{:<26} -> {}", "", hint);
+                    }
+                }
+            }
             "simulate-location" => {
                 let mut lat = None;
                 let mut lng = None;
@@ -124,7 +241,141 @@ Commands:
                     println!("Operation completed")
                 }
             }
-            "reveal-developer-mode" => match device.reveal_developer_mode().await {
+            "forward" | "usbmux-forward" => {
+                let usage = if command == "forward" {
+                    "Usage: forward [<LOCAL_PORT> <DEVICE_PORT>]"
+                } else {
+                    "Usage: usbmux-forward <LOCAL_PORT> <DEVICE_PORT> [-udid <UDID>]"
+                };
+                let ports = (parts.next(), parts.next());
+                if command == "forward" && ports == (None, None) {
+                    if device.forwards().is_empty() {
+                        println!("No forwards")
+                    }
+                    for forward in device.forwards() {
+                        let counters = &forward.counters;
+                        println!(
+                            "{} -> {}  sent {} B, received {} B, {} open / {} connections",
+                            forward.local_addr,
+                            forward.target,
+                            counters.sent.load(Ordering::Relaxed),
+                            counters.received.load(Ordering::Relaxed),
+                            counters.active.load(Ordering::Relaxed),
+                            counters.connections.load(Ordering::Relaxed),
+                        );
+                    }
+                    continue;
+                }
+                let (Some(Ok(local_port)), Some(Ok(device_port))) = (
+                    ports.0.map(str::parse::<u16>),
+                    ports.1.map(str::parse::<u16>),
+                ) else {
+                    println!("{}", usage);
+                    continue;
+                };
+                let local_addr = SocketAddr::from((Ipv4Addr::LOCALHOST, local_port));
+                let result = if command == "forward" {
+                    device.forward(local_addr, device_port).await
+                } else {
+                    device
+                        .usbmux_forward(local_addr, device_port, udid_arg(parts).as_deref())
+                        .await
+                };
+                match result {
+                    Ok(forward) => {
+                        println!("Forwarding {} to {}", forward.local_addr, forward.target)
+                    }
+                    Err(error) => println!("{}", error),
+                }
+            }
+            "socks" => {
+                let local_port = match parts.next().map(str::parse::<u16>) {
+                    None => DEFAULT_SOCKS_PORT,
+                    Some(Ok(local_port)) => local_port,
+                    Some(Err(_)) => {
+                        println!("Usage: socks [<LOCAL_PORT>]");
+                        continue;
+                    }
+                };
+                let local_addr = SocketAddr::from((Ipv4Addr::LOCALHOST, local_port));
+                match device.socks_proxy(local_addr).await {
+                    Ok(forward) => {
+                        println!(
+                            "SOCKS5 proxy on {} to {}",
+                            forward.local_addr, forward.target
+                        )
+                    }
+                    Err(error) => println!("{}", error),
+                }
+            }
+            "unforward" => match parts.next().map(str::parse::<u16>) {
+                Some(Ok(local_port)) => {
+                    if device.stop_forward(local_port) {
+                        println!("Stopped forwarding port {}", local_port)
+                    } else {
+                        println!("No forward on port {}", local_port)
+                    }
+                }
+                _ => println!("Usage: unforward <LOCAL_PORT>"),
+            },
+            "pair" => {
+                println!("Tap \"Trust\" on the device to continue");
+                match device.pair(udid_arg(parts).as_deref()).await {
+                    Ok(record) => println!("Paired, HostID={}", record.host_id),
+                    Err(error) => println!("{}", error),
+                }
+            }
+            "unpair" => match device.unpair(udid_arg(parts).as_deref()).await {
+                Ok(()) => println!("Unpaired"),
+                Err(error) => println!("{}", error),
+            },
+            "validate-pair" => match device.validate_pair(udid_arg(parts).as_deref()).await {
+                Ok(()) => println!("Pair record is valid"),
+                Err(error) => println!("{}", error),
+            },
+            "export-pair-record" | "import-pair-record" => {
+                let Some(file) = parts.next() else {
+                    println!("Usage: {} <FILE> [-udid <UDID>]", command);
+                    continue;
+                };
+                let path = std::path::Path::new(file);
+                let udid = udid_arg(parts);
+                if command == "export-pair-record" && passphrase.is_none() {
+                    println!(
+                        "Warning: no passphrase set, the host private key is written unencrypted"
+                    );
+                }
+                let result = if command == "export-pair-record" {
+                    device
+                        .export_pair_record(udid.as_deref(), path, passphrase.as_ref())
+                        .await
+                } else {
+                    device
+                        .import_pair_record(udid.as_deref(), path, passphrase.as_ref())
+                        .await
+                };
+                match result {
+                    Ok(record) if command == "export-pair-record" => {
+                        println!("Exported HostID={} to {}", record.host_id, path.display())
+                    }
+                    Ok(record) => println!("Imported HostID={}", record.host_id),
+                    Err(error) => println!("{}", error),
+                }
+            }
+            "enable-developer-mode" => {
+                println!("The device restarts once, this takes a few minutes");
+                match device
+                    .enable_developer_mode(udid_arg(parts).as_deref())
+                    .await
+                {
+                    Ok(()) => println!("Developer mode enabled"),
+                    Err(error) => println!("{}", error),
+                }
+            }
+            "reveal-developer-mode" => match device
+                .reveal_developer_mode(udid_arg(parts).as_deref())
+                .await
+            {
                 Ok(_) => {
                     println!("Operation completed")
                 }
@@ -132,6 +383,134 @@ Commands:
                     println!("{}", err)
                 }
             },
+            "pair-records" => {
+                if let Some(source) = parts.next() {
+                    device.pair_record_store = match source {
+                        "usbmuxd" => None,
+                        "system" => Some(PairRecordStore::system()),
+                        dir => Some(PairRecordStore::new(dir)),
+                    };
+                    if let Some(store) = device.pair_record_store.as_mut() {
+                        store.passphrase = passphrase.clone();
+                    }
+                }
+                match &device.pair_record_store {
+                    Some(store) if store.passphrase.is_some() => {
+                        println!("Pair records: {} (encrypted)", store.path.display())
+                    }
+                    Some(store) => println!("Pair records: {}", store.path.display()),
+                    None => println!("Pair records: usbmuxd"),
+                }
+            }
+            "passphrase" => {
+                if parts.next() == Some("clear") {
+                    passphrase = None;
+                } else {
+                    match rpassword::prompt_password("Passphrase: ") {
+                        Ok(line) => passphrase = Some(Passphrase::new(line)),
+                        Err(_) => {
+                            println!("Failed to read input");
+                            continue;
+                        }
+                    }
+                }
+                match device.pair_record_store.as_mut() {
+                    Some(store) => store.passphrase = passphrase.clone(),
+                    None if passphrase.is_some() => {
+                        println!("usbmuxd keeps its own records, choose a store with pair-records")
+                    }
+                    None => {}
+                }
+            }
+            "connection" => {
+                let usbmux = |connection_type| (DeviceConnection::Usbmux, connection_type);
+                let choice = match parts.next() {
+                    Some("usb") => Some(usbmux(Some(ConnectionType::Usb))),
+                    Some("network") => Some(usbmux(Some(ConnectionType::Network))),
+                    Some("any") => Some(usbmux(None)),
+                    Some("direct") => match parts.next().map(str::parse) {
+                        Some(Ok(address)) => Some((DeviceConnection::Direct(address), None)),
+                        _ => {
+                            println!("Usage: connection direct <IP>");
+                            None
+                        }
+                    },
+                    Some(_) => {
+                        println!("Expected usb, network, any or direct <IP>");
+                        None
+                    }
+                    None => None,
+                };
+                if let Some((device_connection, connection_type)) = choice {
+                    device.device_connection = device_connection;
+                    device.connection_type = connection_type;
+                }
+                match (&device.device_connection, &device.connection_type) {
+                    (DeviceConnection::Direct(address), _) => {
+                        println!("Connection: direct to {}", address)
+                    }
+                    (_, Some(connection_type)) => println!("Connection: {:?}", connection_type),
+                    (_, None) => println!("Connection: any (USB first)"),
+                }
+            }
+            "wifi-connections" => {
+                let enabled = match parts.next() {
+                    Some("on") => true,
+                    Some("off") => false,
+                    _ => {
+                        println!("Usage: wifi-connections on | off [-udid <UDID>]");
+                        continue;
+                    }
+                };
+                match device
+                    .set_wifi_connections(udid_arg(parts).as_deref(), enabled)
+                    .await
+                {
+                    Ok(()) if enabled => println!("Wi-Fi connections allowed"),
+                    Ok(()) => println!("Wi-Fi connections disabled"),
+                    Err(error) => println!("{}", error),
+                }
+            }
+            "pin-device-cert" => {
+                match parts.next() {
+                    Some("on") => device.pin_device_certificate = true,
+                    Some("off") => device.pin_device_certificate = false,
+                    Some(_) => println!("Expected on or off"),
+                    None => {}
+                }
+                if device.pin_device_certificate {
+                    println!("Device certificate pinning: on")
+                } else {
+                    println!("Device certificate pinning: off (any device certificate is accepted)")
+                }
+            }
+
+            "tunnel-mode" => {
+                match parts.next() {
+                    Some("interface") => device.tunnel_mode = TunnelMode::Interface,
+                    Some("userspace") => device.tunnel_mode = TunnelMode::Userspace,
+                    Some(_) => println!("Expected interface or userspace"),
+                    None => {}
+                }
+                match device.tunnel_mode {
+                    TunnelMode::Interface => println!("Tunnel mode: interface"),
+                    TunnelMode::Userspace => {
+                        println!("Tunnel mode: userspace (no adapter, only this program can reach the device)")
+                    }
+                }
+            }
+            "plist-format" => {
+                match parts.next() {
+                    Some("xml") => device.plist_format = PlistFormat::Xml,
+                    Some("binary") => device.plist_format = PlistFormat::Binary,
+                    Some(_) => println!("Expected xml or binary"),
+                    None => {}
+                }
+                match device.plist_format {
+                    PlistFormat::Xml => println!("Plist format: xml"),
+                    PlistFormat::Binary => println!("Plist format: binary"),
+                }
+            }
 
             "exit" | "quit" => break,
             _ => println!("Unknown command"),
@@ -139,14 +518,29 @@ Commands:
     }
 }
 
+/// Returns the value following `-udid`, if any.
+fn udid_arg<'a>(mut args: impl Iterator<Item = &'a str>) -> Option<String> {
+    while let Some(arg) = args.next() {
+        if arg == "-udid" {
+            return args.next().map(|val| val.to_string());
+        }
+    }
+    None
+}
+
 #[cfg(test)]
 mod tests {
-    use std::{io::Write, time::Duration};
-
     use super::*;
-    use std::io::{self, BufRead};
-    use tokio;
 
     #[tokio::test]
     async fn test_device() {}
+
+    #[test]
+    fn test_udid_arg() {
+        assert_eq!(
+            udid_arg("-udid 00008030-001A".split_whitespace()).as_deref(),
+            Some("00008030-001A")
+        );
+        assert_eq!(udid_arg("".split_whitespace()), None);
+    }
 }
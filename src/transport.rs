@@ -0,0 +1,13 @@
+use tokio::io::{AsyncRead, AsyncWrite};
+
+/// A duplex byte stream the protocol handlers can run over.
+///
+/// Implemented for anything that is `AsyncRead + AsyncWrite`, so TCP and Unix
+/// sockets, TLS streams, tunneled streams and `tokio::io::duplex` pipes can all
+/// be used interchangeably.
+pub trait Transport: AsyncRead + AsyncWrite + Unpin + Send {}
+
+impl<T: AsyncRead + AsyncWrite + Unpin + Send> Transport for T {}
+
+/// A type-erased [`Transport`].
+pub type BoxedTransport = Box<dyn Transport>;
@@ -1,69 +1,144 @@
+pub mod errors;
+#[cfg(target_os = "linux")]
+pub mod linux;
+pub mod userspace;
+#[cfg(windows)]
+pub mod windows;
+
 use std::{
-    io::Write,
-    net::{IpAddr, Ipv6Addr},
-    os::windows::process::CommandExt,
-    path::PathBuf,
+    net::Ipv6Addr,
+    path::Path,
     sync::{Arc, RwLock},
-    thread,
     time::Duration,
 };
 use tokio::{
-    io::{AsyncReadExt, AsyncWriteExt, ReadHalf, WriteHalf},
+    io::{AsyncRead, AsyncReadExt, AsyncWriteExt, ReadHalf, WriteHalf},
     net::TcpStream,
-    sync::mpsc::UnboundedReceiver,
 };
 
+use errors::TunnelError;
 use log::error;
-use std::process::Command;
-use tokio_rustls::client::TlsStream;
-use wintun::{Session, Wintun};
 
-use crate::usbmux::UsbMuxClient;
+use crate::transport::BoxedTransport;
 
 const IPV6_HEADER_SIZE: usize = 40;
-const LOOPBACK_HEADER: [u8; 4] = [0x00, 0x00, 0x86, 0xdd];
+/// How often tasks blocked on the tunnel check their termination token.
+const TERMINATION_POLL_INTERVAL: Duration = Duration::from_secs(2);
+/// Prefix length of the address assigned to the tunnel interface.
+pub const PREFIX_LENGTH: u8 = 64;
+
+/// How the host reaches the device's services through the tunnel.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum TunnelMode {
+    /// Through a network interface from a [`TunBackend`], which other
+    /// programs can use too. Needs administrator rights.
+    #[default]
+    Interface,
+    /// Through [`userspace::UserspaceStack`], from this process only. Needs no
+    /// rights and no driver.
+    Userspace,
+}
+
+/// Opens TCP connections to addresses on the tunnel.
+#[derive(Clone)]
+pub enum Dialer {
+    /// Through the host network stack, which routes the tunnel prefix to the
+    /// tunnel interface.
+    Interface,
+    /// Through a [`userspace::UserspaceStack`].
+    Userspace(userspace::Connector),
+}
+
+impl Dialer {
+    pub async fn connect(
+        &self,
+        address: Ipv6Addr,
+        port: u16,
+    ) -> Result<BoxedTransport, TunnelError> {
+        match self {
+            Dialer::Interface => Ok(Box::new(TcpStream::connect((address, port)).await?)),
+            Dialer::Userspace(connector) => connector.connect(address, port).await,
+        }
+    }
+}
+
+/// A virtual network interface carrying the tunnel's IPv6 packets on the
+/// host. Packet reads block, so the tunnel runs them on a blocking thread.
+pub trait TunBackend: Send + Sync {
+    /// Assigns `address` to the interface with a /64 prefix.
+    fn set_address(&self, address: Ipv6Addr) -> Result<(), TunnelError>;
+    fn set_mtu(&self, mtu: u32) -> Result<(), TunnelError>;
+    /// Blocks until the host sends a packet through the interface. Fails with
+    /// [`TunnelError::Closed`] once the backend is shut down.
+    fn read_packet(&self) -> Result<Vec<u8>, TunnelError>;
+    /// Delivers a packet from the device to the host.
+    fn write_packet(&self, packet: &[u8]) -> Result<(), TunnelError>;
+    /// Wakes up a blocked `read_packet`.
+    fn shutdown(&self);
+}
+
+/// Creates the interface for this platform: a Wintun adapter, with the driver
+/// loaded from `wintun_path`, on Windows and a `/dev/net/tun` interface on
+/// Linux.
+#[cfg_attr(not(windows), allow(unused_variables))]
+pub fn create_backend(wintun_path: &Path) -> Result<Arc<dyn TunBackend>, TunnelError> {
+    #[cfg(windows)]
+    return Ok(Arc::new(windows::WintunBackend::create(wintun_path)?));
+    #[cfg(target_os = "linux")]
+    {
+        let tun = linux::LinuxTun::create(linux::INTERFACE_NAME)?;
+        log::info!("Created tunnel interface {}", tun.name);
+        return Ok(Arc::new(tun));
+    }
+    #[cfg(not(any(windows, target_os = "linux")))]
+    Err(unsupported_platform())
+}
+
+/// Checks that [`create_backend`] can work, returning what it would use.
+#[cfg_attr(not(windows), allow(unused_variables))]
+pub fn check_backend(wintun_path: &Path) -> Result<String, TunnelError> {
+    #[cfg(windows)]
+    return windows::WintunBackend::check(wintun_path)
+        .map(|()| format!("Wintun at {}", wintun_path.display()));
+    #[cfg(target_os = "linux")]
+    return linux::LinuxTun::check().map(|()| "/dev/net/tun".to_string());
+    #[cfg(not(any(windows, target_os = "linux")))]
+    Err(unsupported_platform())
+}
+
+#[cfg(not(any(windows, target_os = "linux")))]
+fn unsupported_platform() -> TunnelError {
+    TunnelError::Backend(format!("no tunnel backend for {}", std::env::consts::OS))
+}
 
 pub struct Tunnel {
-    pub wintun: Arc<Session>,
+    pub backend: Arc<dyn TunBackend>,
+    /// Largest IPv6 packet, header included, accepted from the device.
+    /// Defaults to the tunnel MTU.
+    pub max_packet_size: usize,
     termination_token: Arc<RwLock<bool>>,
 }
 
 impl Tunnel {
-    pub fn new(ipv6: String, mtu: u32, wintun_path: PathBuf) -> Tunnel {
-        let tun = unsafe { wintun::load_from_path(wintun_path) }.unwrap();
-        let adapter = wintun::Adapter::create(&tun, "wintun", "smt", None).unwrap();
-        let session = Arc::new(adapter.start_session(wintun::MAX_RING_CAPACITY).unwrap());
-        Command::new("netsh")
-            .arg("interface")
-            .arg("ipv6")
-            .arg("set")
-            .arg("address")
-            .arg("interface=\"wintun\"")
-            .arg(format!("address={}/64", ipv6))
-            .creation_flags(0x08000000)
-            .output()
-            .unwrap();
-        Command::new("netsh")
-            .arg("interface")
-            .arg("ipv6")
-            .arg("set")
-            .arg("subinterface")
-            .arg("interface=\"wintun\"")
-            .arg(format!("mtu={}", mtu))
-            .creation_flags(0x08000000)
-            .output()
-            .unwrap();
-        thread::sleep(Duration::from_millis(2000));
-        Tunnel {
-            wintun: session,
+    /// Configures `backend` with the address and MTU handed out by the device.
+    pub fn new(
+        address: Ipv6Addr,
+        mtu: u32,
+        backend: Arc<dyn TunBackend>,
+    ) -> Result<Tunnel, TunnelError> {
+        backend.set_address(address)?;
+        backend.set_mtu(mtu)?;
+        Ok(Tunnel {
+            backend,
+            max_packet_size: mtu as usize,
             termination_token: Arc::new(RwLock::new(false)),
-        }
+        })
     }
 
-    // Spawns a blocking task to read from Wintun and forward IPv6 packets to the async writer.
+    // Spawns a blocking task to read from the interface and forward IPv6 packets to the async writer.
     fn spawn_tun_reader(
         &self,
-        read_session: Arc<Session>,
+        backend: Arc<dyn TunBackend>,
         termination_token: Arc<RwLock<bool>>,
         packet_tx: tokio::sync::mpsc::UnboundedSender<Vec<u8>>,
     ) -> tokio::task::JoinHandle<()> {
@@ -73,13 +148,18 @@ impl Tunnel {
                 if *(termination_token.read().unwrap()) {
                     break;
                 }
-                let mut packet = read_session.receive_blocking().unwrap();
+                let packet = match backend.read_packet() {
+                    Ok(packet) => packet,
+                    Err(TunnelError::Closed) => break,
+                    Err(e) => {
+                        error!("Tunnel interface read failed: {}", e);
+                        break;
+                    }
+                };
 
-                let bytes = packet.bytes_mut();
-                let ip_version = (bytes[0] >> 4) & 0x0f;
                 // Only forward IPv6 packets
-                if ip_version == 6 {
-                    if packet_tx.send(bytes.to_vec()).is_err() {
+                if packet.first().map(|byte| byte >> 4) == Some(6) {
+                    if packet_tx.send(packet).is_err() {
                         break;
                     }
                 }
@@ -87,10 +167,25 @@ impl Tunnel {
         })
     }
 
+    // Spawns a blocking task to write the packets from the device to the interface.
+    fn spawn_tun_writer(
+        &self,
+        backend: Arc<dyn TunBackend>,
+        mut packet_rx: tokio::sync::mpsc::UnboundedReceiver<Vec<u8>>,
+    ) -> tokio::task::JoinHandle<()> {
+        tokio::task::spawn_blocking(move || {
+            while let Some(packet) = packet_rx.blocking_recv() {
+                if let Err(e) = backend.write_packet(&packet) {
+                    error!("Tunnel interface write failed: {}", e);
+                }
+            }
+        })
+    }
+
     // Spawns an async task to receive bytes and write to the network writer.
     fn spawn_writer_task(
         &self,
-        mut writer: WriteHalf<TlsStream<TcpStream>>,
+        mut writer: WriteHalf<BoxedTransport>,
         termination_token: Arc<RwLock<bool>>,
         mut packet_rx: tokio::sync::mpsc::UnboundedReceiver<Vec<u8>>,
     ) -> tokio::task::JoinHandle<()> {
@@ -99,15 +194,19 @@ impl Tunnel {
                 if *(termination_token.read().unwrap()) {
                     break;
                 }
-                writer.write_all(&bytes).await.unwrap();
+                if let Err(e) = writer.write_all(&bytes).await {
+                    error!("Tunnel write failed: {}", e);
+                    *termination_token.write().unwrap() = true;
+                    break;
+                }
             }
         })
     }
 
     pub async fn on(
         &mut self,
-        mut reader: ReadHalf<TlsStream<TcpStream>>,
-        mut writer: WriteHalf<TlsStream<TcpStream>>,
+        mut reader: ReadHalf<BoxedTransport>,
+        mut writer: WriteHalf<BoxedTransport>,
     ) -> (
         tokio::task::JoinHandle<()>,
         tokio::task::JoinHandle<()>,
@@ -116,60 +215,151 @@ impl Tunnel {
         // Reset termination token
         *self.termination_token.write().unwrap() = false;
 
-        let read_session = Arc::clone(&self.wintun);
-
         let (packet_tx, packet_rx) = tokio::sync::mpsc::unbounded_channel::<Vec<u8>>();
 
         // Spawn tasks
-        let tun_read_handle =
-            self.spawn_tun_reader(read_session, self.termination_token.clone(), packet_tx);
+        let tun_read_handle = self.spawn_tun_reader(
+            self.backend.clone(),
+            self.termination_token.clone(),
+            packet_tx,
+        );
 
         let writer_handle =
             self.spawn_writer_task(writer, self.termination_token.clone(), packet_rx);
 
+        let (incoming_tx, incoming_rx) = tokio::sync::mpsc::unbounded_channel::<Vec<u8>>();
+        // Ends once the socket reader drops `incoming_tx`.
+        self.spawn_tun_writer(self.backend.clone(), incoming_rx);
+
         let tt2 = self.termination_token.clone();
 
         // Handle network -> tunnel
-        let write_session = Arc::clone(&self.wintun);
+        let backend = self.backend.clone();
+        let max_packet_size = self.max_packet_size;
         let sock_read_handle = tokio::task::spawn(async move {
-            let mut ipv6_header = [0u8; IPV6_HEADER_SIZE];
-            loop {
-                if *(tt2.read().unwrap()) {
-                    break;
-                }
-                let mut is_error = false;
-                tokio::select! {
-                _ = async {
-                 reader.read_exact(&mut ipv6_header).await.unwrap();
-                let ipv6_length = u16::from_be_bytes([ipv6_header[4], ipv6_header[5]]) as usize;
-
-                let mut ipv6_body = vec![0u8; ipv6_length];
-                reader.read_exact(&mut ipv6_body).await.unwrap();
-
-                let full_packet = [&ipv6_header[..], &ipv6_body[..]].concat();
-
-                // Allocate and send packet correctly
-                let mut send_pkt = write_session
-                    .allocate_send_packet(full_packet.len() as u16)
-                    .expect("Packet allocation failed");
-                send_pkt.bytes_mut().copy_from_slice(&full_packet);
-                write_session.send_packet(send_pkt);
-
-                } => {},
-                _ = tokio::time::sleep(Duration::from_secs(2)) => {
-                      if *(tt2.read().unwrap()) {
+            while let Some(packet) =
+                read_ipv6_packet_until_terminated(&mut reader, max_packet_size, &tt2).await
+            {
+                let full_packet = match packet {
+                    Ok(packet) => packet,
+                    Err(e) => {
+                        error!("Tunnel read failed: {}", e);
+                        *tt2.write().unwrap() = true;
+                        break;
+                    }
+                };
+
+                if incoming_tx.send(full_packet).is_err() {
                     break;
                 }
-                }
-                        }
             }
+            // Unblock the interface reader
+            backend.shutdown();
         });
 
         (sock_read_handle, tun_read_handle, writer_handle)
     }
 
+    /// Returns a clone of the token checked by the tunnel tasks, so the tunnel
+    /// can be stopped from another task.
+    pub fn termination_token(&self) -> Arc<RwLock<bool>> {
+        self.termination_token.clone()
+    }
+
     pub fn terminate(&mut self) {
-        let mut guard = self.termination_token.write().unwrap();
-        *guard = true;
+        *self.termination_token.write().unwrap() = true;
+        self.backend.shutdown();
+    }
+}
+
+/// Resolves once `termination_token` is set.
+pub async fn terminated(termination_token: &RwLock<bool>) {
+    while !*(termination_token.read().unwrap()) {
+        tokio::time::sleep(TERMINATION_POLL_INTERVAL).await;
+    }
+}
+
+/// Reads one IPv6 packet, header included, from the tunnel stream.
+async fn read_ipv6_packet<R: AsyncRead + Unpin>(
+    reader: &mut R,
+    max_packet_size: usize,
+) -> Result<Vec<u8>, TunnelError> {
+    let mut packet = vec![0u8; IPV6_HEADER_SIZE];
+    reader.read_exact(&mut packet).await?;
+
+    let length = IPV6_HEADER_SIZE + u16::from_be_bytes([packet[4], packet[5]]) as usize;
+    if length > max_packet_size {
+        return Err(TunnelError::FrameTooLarge {
+            length,
+            max: max_packet_size,
+        });
+    }
+
+    packet.resize(length, 0);
+    reader.read_exact(&mut packet[IPV6_HEADER_SIZE..]).await?;
+    Ok(packet)
+}
+
+/// Reads the next packet with [`read_ipv6_packet`], checking
+/// `termination_token` meanwhile. Returns `None` once the tunnel is
+/// terminated.
+///
+/// The read is kept across checks rather than raced against a timer:
+/// `read_exact` is not cancel-safe, and dropping it partway through a packet
+/// would leave every later packet misaligned.
+async fn read_ipv6_packet_until_terminated<R: AsyncRead + Unpin>(
+    reader: &mut R,
+    max_packet_size: usize,
+    termination_token: &RwLock<bool>,
+) -> Option<Result<Vec<u8>, TunnelError>> {
+    let packet = read_ipv6_packet(reader, max_packet_size);
+    tokio::pin!(packet);
+    loop {
+        if *(termination_token.read().unwrap()) {
+            return None;
+        }
+        tokio::select! {
+            packet = &mut packet => return Some(packet),
+            _ = tokio::time::sleep(TERMINATION_POLL_INTERVAL) => {}
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use tokio::io::AsyncWriteExt;
+
+    use super::{errors::TunnelError, read_ipv6_packet, IPV6_HEADER_SIZE};
+
+    #[tokio::test]
+    async fn test_read_ipv6_packet() {
+        let (mut client_end, mut server) = tokio::io::duplex(4096);
+
+        let mut packet = vec![0u8; IPV6_HEADER_SIZE + 8];
+        packet[0] = 0x60;
+        packet[4..6].copy_from_slice(&8u16.to_be_bytes());
+        server.write_all(&packet).await.unwrap();
+        assert_eq!(
+            read_ipv6_packet(&mut client_end, 1280).await.unwrap(),
+            packet
+        );
+
+        let mut header = [0u8; IPV6_HEADER_SIZE];
+        header[4..6].copy_from_slice(&u16::MAX.to_be_bytes());
+        server.write_all(&header).await.unwrap();
+        assert!(matches!(
+            read_ipv6_packet(&mut client_end, 1280).await,
+            Err(TunnelError::FrameTooLarge {
+                length: 65575,
+                max: 1280
+            })
+        ));
+
+        server.write_all(&header[..10]).await.unwrap();
+        drop(server);
+        assert!(matches!(
+            read_ipv6_packet(&mut client_end, 1280).await,
+            Err(TunnelError::Io(_))
+        ));
     }
 }
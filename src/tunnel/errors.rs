@@ -1 +1,11 @@
-pub enum TunnelError {}
+#[derive(Debug, thiserror::Error)]
+pub enum TunnelError {
+    #[error("IO error: {0}")]
+    Io(#[from] std::io::Error),
+    #[error("Packet of {length} bytes exceeds the {max} byte limit")]
+    FrameTooLarge { length: usize, max: usize },
+    #[error("Tunnel backend unavailable: {0}")]
+    Backend(String),
+    #[error("Tunnel interface closed")]
+    Closed,
+}
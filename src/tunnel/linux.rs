@@ -0,0 +1,330 @@
+use std::{
+    ffi::CStr,
+    fs::{File, OpenOptions},
+    io::{self, Read, Write},
+    mem,
+    net::Ipv6Addr,
+    os::{
+        fd::{AsRawFd, FromRawFd, OwnedFd},
+        unix::fs::OpenOptionsExt,
+    },
+};
+
+use super::{errors::TunnelError, TunBackend, PREFIX_LENGTH};
+
+/// Interface name template; the kernel fills in the number.
+pub const INTERFACE_NAME: &str = "rusty%d";
+const TUN_DEVICE: &str = "/dev/net/tun";
+/// Largest packet read from the interface.
+const MAX_PACKET_SIZE: usize = 65535;
+const NLMSG_HEADER_LEN: usize = 16;
+const RTA_ALIGNTO: usize = 4;
+
+/// A `/dev/net/tun` interface, configured over rtnetlink. Needs root or
+/// `CAP_NET_ADMIN`.
+pub struct LinuxTun {
+    device: File,
+    /// Eventfd that wakes blocked reads on shutdown.
+    shutdown: File,
+    /// Interface name picked by the kernel.
+    pub name: String,
+    index: u32,
+}
+
+impl LinuxTun {
+    /// Checks that a TUN interface can be created.
+    pub fn check() -> Result<(), TunnelError> {
+        LinuxTun::create(INTERFACE_NAME).map(|_| ())
+    }
+
+    /// Creates a TUN interface named after `name`, which may contain `%d`,
+    /// and brings it up. The interface is removed when dropped.
+    pub fn create(name: &str) -> Result<Self, TunnelError> {
+        if name.len() >= libc::IFNAMSIZ {
+            return Err(TunnelError::Backend(format!(
+                "interface name too long: {}",
+                name
+            )));
+        }
+        let device = OpenOptions::new()
+            .read(true)
+            .write(true)
+            .custom_flags(libc::O_CLOEXEC)
+            .open(TUN_DEVICE)
+            .map_err(|e| TunnelError::Backend(format!("{}: {}", TUN_DEVICE, e)))?;
+
+        let mut request: libc::ifreq = unsafe { mem::zeroed() };
+        for (dst, src) in request.ifr_name.iter_mut().zip(name.bytes()) {
+            *dst = src as libc::c_char;
+        }
+        request.ifr_ifru.ifru_flags = (libc::IFF_TUN | libc::IFF_NO_PI) as libc::c_short;
+        if unsafe { libc::ioctl(device.as_raw_fd(), libc::TUNSETIFF, &mut request) } < 0 {
+            return Err(TunnelError::Backend(format!(
+                "{}: {}",
+                TUN_DEVICE,
+                io::Error::last_os_error()
+            )));
+        }
+        let name = unsafe { CStr::from_ptr(request.ifr_name.as_ptr()) }
+            .to_string_lossy()
+            .into_owned();
+        let index = unsafe { libc::if_nametoindex(request.ifr_name.as_ptr()) };
+        if index == 0 {
+            return Err(io::Error::last_os_error().into());
+        }
+
+        let shutdown = unsafe { libc::eventfd(0, libc::EFD_CLOEXEC) };
+        if shutdown < 0 {
+            return Err(io::Error::last_os_error().into());
+        }
+        let shutdown = File::from(unsafe { OwnedFd::from_raw_fd(shutdown) });
+
+        Netlink::open()?.request(
+            libc::RTM_NEWLINK,
+            0,
+            &link_message(index, libc::IFF_UP as u32, None),
+        )?;
+        Ok(LinuxTun {
+            device,
+            shutdown,
+            name,
+            index,
+        })
+    }
+}
+
+impl TunBackend for LinuxTun {
+    fn set_address(&self, address: Ipv6Addr) -> Result<(), TunnelError> {
+        Netlink::open()?.request(
+            libc::RTM_NEWADDR,
+            (libc::NLM_F_CREATE | libc::NLM_F_EXCL) as u16,
+            &address_message(self.index, address),
+        )
+    }
+
+    fn set_mtu(&self, mtu: u32) -> Result<(), TunnelError> {
+        Netlink::open()?.request(
+            libc::RTM_NEWLINK,
+            0,
+            &link_message(self.index, libc::IFF_UP as u32, Some(mtu)),
+        )
+    }
+
+    fn read_packet(&self) -> Result<Vec<u8>, TunnelError> {
+        let mut fds = [
+            libc::pollfd {
+                fd: self.device.as_raw_fd(),
+                events: libc::POLLIN,
+                revents: 0,
+            },
+            libc::pollfd {
+                fd: self.shutdown.as_raw_fd(),
+                events: libc::POLLIN,
+                revents: 0,
+            },
+        ];
+        loop {
+            if unsafe { libc::poll(fds.as_mut_ptr(), fds.len() as libc::nfds_t, -1) } < 0 {
+                let error = io::Error::last_os_error();
+                if error.kind() == io::ErrorKind::Interrupted {
+                    continue;
+                }
+                return Err(error.into());
+            }
+            if fds[1].revents != 0 {
+                return Err(TunnelError::Closed);
+            }
+            if fds[0].revents != 0 {
+                let mut packet = vec![0u8; MAX_PACKET_SIZE];
+                let length = (&self.device).read(&mut packet)?;
+                packet.truncate(length);
+                return Ok(packet);
+            }
+        }
+    }
+
+    fn write_packet(&self, packet: &[u8]) -> Result<(), TunnelError> {
+        // The device takes one packet per write.
+        if (&self.device).write(packet)? != packet.len() {
+            return Err(io::Error::from(io::ErrorKind::WriteZero).into());
+        }
+        Ok(())
+    }
+
+    fn shutdown(&self) {
+        let _ = (&self.shutdown).write(&1u64.to_ne_bytes());
+    }
+}
+
+/// A `NETLINK_ROUTE` socket for configuring interfaces.
+struct Netlink {
+    socket: OwnedFd,
+    sequence: u32,
+}
+
+impl Netlink {
+    fn open() -> Result<Self, TunnelError> {
+        let socket = unsafe {
+            libc::socket(
+                libc::AF_NETLINK,
+                libc::SOCK_RAW | libc::SOCK_CLOEXEC,
+                libc::NETLINK_ROUTE,
+            )
+        };
+        if socket < 0 {
+            return Err(io::Error::last_os_error().into());
+        }
+        Ok(Netlink {
+            socket: unsafe { OwnedFd::from_raw_fd(socket) },
+            sequence: 0,
+        })
+    }
+
+    /// Sends a request and waits for the kernel to acknowledge it.
+    fn request(&mut self, kind: u16, flags: u16, body: &[u8]) -> Result<(), TunnelError> {
+        self.sequence += 1;
+        let message = netlink_message(kind, flags, self.sequence, body);
+        let mut address: libc::sockaddr_nl = unsafe { mem::zeroed() };
+        address.nl_family = libc::AF_NETLINK as libc::sa_family_t;
+        let sent = unsafe {
+            libc::sendto(
+                self.socket.as_raw_fd(),
+                message.as_ptr().cast(),
+                message.len(),
+                0,
+                (&address as *const libc::sockaddr_nl).cast(),
+                mem::size_of::<libc::sockaddr_nl>() as libc::socklen_t,
+            )
+        };
+        if sent < 0 {
+            return Err(io::Error::last_os_error().into());
+        }
+
+        let mut buffer = vec![0u8; 8192];
+        loop {
+            let received = unsafe {
+                libc::recv(
+                    self.socket.as_raw_fd(),
+                    buffer.as_mut_ptr().cast(),
+                    buffer.len(),
+                    0,
+                )
+            };
+            if received < 0 {
+                let error = io::Error::last_os_error();
+                if error.kind() == io::ErrorKind::Interrupted {
+                    continue;
+                }
+                return Err(error.into());
+            }
+            if let Some(result) = parse_ack(&buffer[..received as usize], self.sequence) {
+                return result.map_err(|e| TunnelError::Backend(format!("netlink: {}", e)));
+            }
+        }
+    }
+}
+
+/// Frames `body` as a netlink request asking for an acknowledgement.
+fn netlink_message(kind: u16, flags: u16, sequence: u32, body: &[u8]) -> Vec<u8> {
+    let mut message = Vec::with_capacity(NLMSG_HEADER_LEN + body.len());
+    message.extend_from_slice(&((NLMSG_HEADER_LEN + body.len()) as u32).to_ne_bytes());
+    message.extend_from_slice(&kind.to_ne_bytes());
+    message
+        .extend_from_slice(&(flags | (libc::NLM_F_REQUEST | libc::NLM_F_ACK) as u16).to_ne_bytes());
+    message.extend_from_slice(&sequence.to_ne_bytes());
+    message.extend_from_slice(&0u32.to_ne_bytes());
+    message.extend_from_slice(body);
+    message
+}
+
+/// An `ifinfomsg` setting `flags` and, optionally, the MTU.
+fn link_message(index: u32, flags: u32, mtu: Option<u32>) -> Vec<u8> {
+    let mut message = Vec::new();
+    message.push(libc::AF_UNSPEC as u8);
+    message.push(0);
+    message.extend_from_slice(&0u16.to_ne_bytes());
+    message.extend_from_slice(&index.to_ne_bytes());
+    message.extend_from_slice(&flags.to_ne_bytes());
+    message.extend_from_slice(&flags.to_ne_bytes());
+    if let Some(mtu) = mtu {
+        push_attribute(&mut message, libc::IFLA_MTU, &mtu.to_ne_bytes());
+    }
+    message
+}
+
+/// An `ifaddrmsg` adding `address` with the tunnel prefix. Duplicate address
+/// detection is skipped, as nothing else lives on the tunnel link.
+fn address_message(index: u32, address: Ipv6Addr) -> Vec<u8> {
+    let mut message = Vec::new();
+    message.push(libc::AF_INET6 as u8);
+    message.push(PREFIX_LENGTH);
+    message.push(libc::IFA_F_NODAD as u8);
+    message.push(libc::RT_SCOPE_UNIVERSE);
+    message.extend_from_slice(&index.to_ne_bytes());
+    push_attribute(&mut message, libc::IFA_LOCAL, &address.octets());
+    push_attribute(&mut message, libc::IFA_ADDRESS, &address.octets());
+    message
+}
+
+fn push_attribute(message: &mut Vec<u8>, kind: u16, data: &[u8]) {
+    message.extend_from_slice(&(4 + data.len() as u16).to_ne_bytes());
+    message.extend_from_slice(&kind.to_ne_bytes());
+    message.extend_from_slice(data);
+    message.resize(message.len().next_multiple_of(RTA_ALIGNTO), 0);
+}
+
+/// Finds the acknowledgement of request `sequence` among the messages in
+/// `buffer`.
+fn parse_ack(mut buffer: &[u8], sequence: u32) -> Option<io::Result<()>> {
+    while buffer.len() >= NLMSG_HEADER_LEN {
+        let length = u32::from_ne_bytes(buffer[0..4].try_into().unwrap()) as usize;
+        let kind = u16::from_ne_bytes(buffer[4..6].try_into().unwrap());
+        let message_sequence = u32::from_ne_bytes(buffer[8..12].try_into().unwrap());
+        if length < NLMSG_HEADER_LEN || length > buffer.len() {
+            return Some(Err(io::Error::from(io::ErrorKind::InvalidData)));
+        }
+        if kind == libc::NLMSG_ERROR as u16 && message_sequence == sequence {
+            let Some(code) = buffer.get(NLMSG_HEADER_LEN..NLMSG_HEADER_LEN + 4) else {
+                return Some(Err(io::Error::from(io::ErrorKind::InvalidData)));
+            };
+            return Some(match i32::from_ne_bytes(code.try_into().unwrap()) {
+                0 => Ok(()),
+                code => Err(io::Error::from_raw_os_error(-code)),
+            });
+        }
+        buffer = &buffer[length.next_multiple_of(RTA_ALIGNTO).min(buffer.len())..];
+    }
+    None
+}
+
+#[cfg(test)]
+mod test {
+    use std::net::Ipv6Addr;
+
+    use super::{address_message, netlink_message, parse_ack, NLMSG_HEADER_LEN};
+
+    #[test]
+    fn test_netlink_messages() {
+        let address: Ipv6Addr = "fd35:d15b:2c3d::2".parse().unwrap();
+        let body = address_message(7, address);
+        assert_eq!(body.len(), 8 + 2 * 20);
+        assert_eq!(&body[..4], &[libc::AF_INET6 as u8, 64, 0x02, 0]);
+        assert_eq!(u32::from_ne_bytes(body[4..8].try_into().unwrap()), 7);
+        assert_eq!(&body[12..28], &address.octets());
+
+        let message = netlink_message(libc::RTM_NEWADDR, 0, 3, &body);
+        assert_eq!(
+            u32::from_ne_bytes(message[..4].try_into().unwrap()) as usize,
+            NLMSG_HEADER_LEN + body.len()
+        );
+
+        let mut ack = netlink_message(libc::NLMSG_ERROR as u16, 0, 3, &0i32.to_ne_bytes());
+        assert!(parse_ack(&ack, 2).is_none());
+        assert!(matches!(parse_ack(&ack, 3), Some(Ok(()))));
+
+        ack.truncate(NLMSG_HEADER_LEN);
+        ack.extend_from_slice(&(-libc::EEXIST).to_ne_bytes());
+        let error = parse_ack(&ack, 3).unwrap().unwrap_err();
+        assert_eq!(error.raw_os_error(), Some(libc::EEXIST));
+    }
+}
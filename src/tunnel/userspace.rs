@@ -0,0 +1,629 @@
+use std::{
+    collections::VecDeque,
+    future::{self, Future},
+    io,
+    net::Ipv6Addr,
+    ops::RangeInclusive,
+    pin::Pin,
+    sync::{Arc, RwLock},
+    task::{Context, Poll},
+    time::Duration,
+};
+
+use log::error;
+use smoltcp::{
+    iface::{Config, Interface, SocketHandle, SocketSet},
+    phy::{self, DeviceCapabilities, Medium},
+    socket::tcp,
+    time::Instant,
+    wire::{HardwareAddress, IpAddress, IpCidr},
+};
+use tokio::{
+    io::{AsyncRead, AsyncWrite, AsyncWriteExt, DuplexStream, ReadBuf, ReadHalf, WriteHalf},
+    sync::{mpsc, oneshot},
+    task::JoinHandle,
+    time::Sleep,
+};
+
+use super::{errors::TunnelError, read_ipv6_packet_until_terminated, PREFIX_LENGTH};
+use crate::transport::BoxedTransport;
+
+/// Size of the send and receive buffers of each connection.
+const SOCKET_BUFFER_SIZE: usize = 256 * 1024;
+/// Size of the pipe between a connection and its stream.
+const STREAM_BUFFER_SIZE: usize = 64 * 1024;
+/// Default for [`UserspaceStack::socket_timeout`].
+const SOCKET_TIMEOUT: Duration = Duration::from_secs(10);
+const EPHEMERAL_PORTS: RangeInclusive<u16> = 49152..=65535;
+
+/// An IPv6/TCP stack terminating the tunnel's packets in process, so no
+/// network interface or administrator rights are needed. Connections to the
+/// device are only reachable from this process, through [`connect`].
+///
+/// [`connect`]: UserspaceStack::connect
+pub struct UserspaceStack {
+    /// Host address on the tunnel, handed out by the device.
+    pub address: Ipv6Addr,
+    pub mtu: u32,
+    /// How long the device may leave a connection attempt or sent data
+    /// unacknowledged. Applies to connections opened after `on`.
+    pub socket_timeout: Duration,
+    connector: Connector,
+    termination_token: Arc<RwLock<bool>>,
+}
+
+/// Opens connections through a [`UserspaceStack`]; cheap to clone.
+#[derive(Clone)]
+pub struct Connector {
+    requests: mpsc::UnboundedSender<ConnectRequest>,
+}
+
+impl Connector {
+    /// Opens a TCP connection to `port` on `address` through the tunnel.
+    pub async fn connect(
+        &self,
+        address: Ipv6Addr,
+        port: u16,
+    ) -> Result<BoxedTransport, TunnelError> {
+        let (reply, response) = oneshot::channel();
+        self.requests
+            .send(ConnectRequest {
+                address,
+                port,
+                reply,
+            })
+            .map_err(|_| TunnelError::Closed)?;
+        let stream = response.await.map_err(|_| TunnelError::Closed)??;
+        Ok(Box::new(stream))
+    }
+}
+
+struct ConnectRequest {
+    address: Ipv6Addr,
+    port: u16,
+    reply: oneshot::Sender<io::Result<DuplexStream>>,
+}
+
+impl UserspaceStack {
+    pub fn new(address: Ipv6Addr, mtu: u32) -> Self {
+        // Connections fail until `on` starts the stack.
+        let (requests, _) = mpsc::unbounded_channel();
+        UserspaceStack {
+            address,
+            mtu,
+            socket_timeout: SOCKET_TIMEOUT,
+            connector: Connector { requests },
+            termination_token: Arc::new(RwLock::new(false)),
+        }
+    }
+
+    /// Starts the stack on the tunnel connection. Returns the tasks reading
+    /// the tunnel, running the stack and writing the tunnel.
+    pub async fn on(
+        &mut self,
+        mut reader: ReadHalf<BoxedTransport>,
+        mut writer: WriteHalf<BoxedTransport>,
+    ) -> (JoinHandle<()>, JoinHandle<()>, JoinHandle<()>) {
+        // Reset termination token
+        *self.termination_token.write().unwrap() = false;
+
+        let (requests_tx, requests) = mpsc::unbounded_channel();
+        self.connector = Connector {
+            requests: requests_tx,
+        };
+        let (incoming_tx, incoming) = mpsc::unbounded_channel::<Vec<u8>>();
+        let (outgoing, mut outgoing_rx) = mpsc::unbounded_channel::<Vec<u8>>();
+
+        let termination_token = self.termination_token.clone();
+        let max_packet_size = self.mtu as usize;
+        let sock_read_handle = tokio::spawn(async move {
+            while let Some(packet) =
+                read_ipv6_packet_until_terminated(&mut reader, max_packet_size, &termination_token)
+                    .await
+            {
+                match packet {
+                    Ok(packet) => {
+                        if incoming_tx.send(packet).is_err() {
+                            break;
+                        }
+                    }
+                    Err(e) => {
+                        error!("Tunnel read failed: {}", e);
+                        *termination_token.write().unwrap() = true;
+                        break;
+                    }
+                }
+            }
+        });
+
+        let mut driver = Driver::new(self.address, self.mtu, requests, incoming, outgoing);
+        driver.socket_timeout = self.socket_timeout;
+        let stack_handle = tokio::spawn(async move {
+            future::poll_fn(|cx| driver.poll(cx)).await;
+        });
+
+        let termination_token = self.termination_token.clone();
+        let writer_handle = tokio::spawn(async move {
+            while let Some(packet) = outgoing_rx.recv().await {
+                if let Err(e) = writer.write_all(&packet).await {
+                    error!("Tunnel write failed: {}", e);
+                    *termination_token.write().unwrap() = true;
+                    break;
+                }
+            }
+        });
+
+        (sock_read_handle, stack_handle, writer_handle)
+    }
+
+    /// Opens a TCP connection to `port` on `address` through the tunnel.
+    pub async fn connect(
+        &self,
+        address: Ipv6Addr,
+        port: u16,
+    ) -> Result<BoxedTransport, TunnelError> {
+        self.connector.connect(address, port).await
+    }
+
+    /// Returns a handle opening connections through this stack, for use
+    /// from other tasks. Handles taken before `on` stay unusable.
+    pub fn connector(&self) -> Connector {
+        self.connector.clone()
+    }
+
+    /// Returns a clone of the token checked by the stack tasks, so the stack
+    /// can be stopped from another task.
+    pub fn termination_token(&self) -> Arc<RwLock<bool>> {
+        self.termination_token.clone()
+    }
+
+    pub fn terminate(&mut self) {
+        *self.termination_token.write().unwrap() = true;
+    }
+}
+
+/// Runs the stack: moves packets between the tunnel and smoltcp, and bytes
+/// between smoltcp sockets and the streams handed out by `connect`.
+struct Driver {
+    iface: Interface,
+    device: PacketQueue,
+    sockets: SocketSet<'static>,
+    connections: Vec<Connection>,
+    requests: mpsc::UnboundedReceiver<ConnectRequest>,
+    incoming: mpsc::UnboundedReceiver<Vec<u8>>,
+    outgoing: mpsc::UnboundedSender<Vec<u8>>,
+    timer: Pin<Box<Sleep>>,
+    next_port: u16,
+    socket_timeout: Duration,
+}
+
+impl Driver {
+    fn new(
+        address: Ipv6Addr,
+        mtu: u32,
+        requests: mpsc::UnboundedReceiver<ConnectRequest>,
+        incoming: mpsc::UnboundedReceiver<Vec<u8>>,
+        outgoing: mpsc::UnboundedSender<Vec<u8>>,
+    ) -> Self {
+        let mut seed = [0u8; 8];
+        let _ = aws_lc_rs::rand::fill(&mut seed);
+        let seed = u64::from_ne_bytes(seed);
+
+        let mut device = PacketQueue {
+            received: VecDeque::new(),
+            sent: Vec::new(),
+            mtu: mtu as usize,
+        };
+        let mut config = Config::new(HardwareAddress::Ip);
+        config.random_seed = seed;
+        let mut iface = Interface::new(config, &mut device, Instant::now());
+        iface.update_ip_addrs(|addresses| {
+            let _ = addresses.push(IpCidr::new(IpAddress::Ipv6(address), PREFIX_LENGTH));
+        });
+
+        let ports = EPHEMERAL_PORTS.end() - EPHEMERAL_PORTS.start();
+        Driver {
+            iface,
+            device,
+            sockets: SocketSet::new(Vec::new()),
+            connections: Vec::new(),
+            requests,
+            incoming,
+            outgoing,
+            timer: Box::pin(tokio::time::sleep(Duration::ZERO)),
+            next_port: EPHEMERAL_PORTS.start() + (seed % ports as u64) as u16,
+            socket_timeout: SOCKET_TIMEOUT,
+        }
+    }
+
+    /// Makes all the progress it can. Finishes when the tunnel closes.
+    fn poll(&mut self, cx: &mut Context<'_>) -> Poll<()> {
+        loop {
+            loop {
+                match self.incoming.poll_recv(cx) {
+                    Poll::Ready(Some(packet)) => self.device.received.push_back(packet),
+                    Poll::Ready(None) => return Poll::Ready(()),
+                    Poll::Pending => break,
+                }
+            }
+            while let Poll::Ready(Some(request)) = self.requests.poll_recv(cx) {
+                self.open(request);
+            }
+
+            self.iface
+                .poll(Instant::now(), &mut self.device, &mut self.sockets);
+            let progress = self.pump(cx);
+            for packet in self.device.sent.drain(..) {
+                if self.outgoing.send(packet).is_err() {
+                    return Poll::Ready(());
+                }
+            }
+            if progress {
+                continue;
+            }
+
+            if let Some(delay) = self.iface.poll_delay(Instant::now(), &self.sockets) {
+                let deadline =
+                    tokio::time::Instant::now() + Duration::from_micros(delay.total_micros());
+                self.timer.as_mut().reset(deadline);
+                if self.timer.as_mut().poll(cx).is_ready() {
+                    continue;
+                }
+            }
+            return Poll::Pending;
+        }
+    }
+
+    fn open(&mut self, request: ConnectRequest) {
+        let mut socket = tcp::Socket::new(
+            tcp::SocketBuffer::new(vec![0; SOCKET_BUFFER_SIZE]),
+            tcp::SocketBuffer::new(vec![0; SOCKET_BUFFER_SIZE]),
+        );
+        socket.set_timeout(Some(smoltcp::time::Duration::from_millis(
+            self.socket_timeout.as_millis() as u64,
+        )));
+        let local_port = self.next_port;
+        self.next_port = if local_port == *EPHEMERAL_PORTS.end() {
+            *EPHEMERAL_PORTS.start()
+        } else {
+            local_port + 1
+        };
+        let remote = (IpAddress::Ipv6(request.address), request.port);
+        if let Err(e) = socket.connect(self.iface.context(), remote, local_port) {
+            let _ = request.reply.send(Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                e.to_string(),
+            )));
+            return;
+        }
+
+        let (stream, own_end) = tokio::io::duplex(STREAM_BUFFER_SIZE);
+        self.connections.push(Connection {
+            handle: self.sockets.add(socket),
+            stream: own_end,
+            pending: Some((request.reply, stream)),
+            deadline: tokio::time::Instant::now() + self.socket_timeout,
+            write_closed: false,
+            read_closed: false,
+        });
+    }
+
+    /// Moves data between the sockets and their streams, dropping finished
+    /// connections. Returns whether anything moved.
+    fn pump(&mut self, cx: &mut Context<'_>) -> bool {
+        let mut progress = false;
+        let mut closed = Vec::new();
+        let sockets = &mut self.sockets;
+        self.connections.retain_mut(|connection| {
+            let socket = sockets.get_mut::<tcp::Socket>(connection.handle);
+            let open = connection.pump(socket, cx, &mut progress);
+            if !open {
+                closed.push(connection.handle);
+            }
+            open
+        });
+        for handle in closed {
+            self.sockets.remove(handle);
+        }
+        progress
+    }
+}
+
+/// A TCP connection and the stack's end of the stream handed out for it.
+struct Connection {
+    handle: SocketHandle,
+    stream: DuplexStream,
+    /// Where to send the other end of the stream once the handshake is done.
+    pending: Option<(oneshot::Sender<io::Result<DuplexStream>>, DuplexStream)>,
+    /// When smoltcp gives up on the handshake at the latest. Closing before
+    /// then means the device reset the connection.
+    deadline: tokio::time::Instant,
+    /// Whether the stream's writer is done and a FIN was queued.
+    write_closed: bool,
+    /// Whether the device is done sending and the stream was shut down.
+    read_closed: bool,
+}
+
+impl Connection {
+    /// Returns false once the connection can be dropped.
+    fn pump(
+        &mut self,
+        socket: &mut tcp::Socket,
+        cx: &mut Context<'_>,
+        progress: &mut bool,
+    ) -> bool {
+        if let Some((reply, stream)) = self.pending.take() {
+            match socket.state() {
+                tcp::State::SynSent if !reply.is_closed() => {
+                    self.pending = Some((reply, stream));
+                    return true;
+                }
+                tcp::State::SynSent => {
+                    socket.abort();
+                    return false;
+                }
+                tcp::State::Closed => {
+                    let error = if tokio::time::Instant::now() >= self.deadline {
+                        io::Error::new(
+                            io::ErrorKind::TimedOut,
+                            "the device did not answer the connection attempt",
+                        )
+                    } else {
+                        io::Error::new(
+                            io::ErrorKind::ConnectionRefused,
+                            "the device did not accept the connection",
+                        )
+                    };
+                    let _ = reply.send(Err(error));
+                    return false;
+                }
+                _ => {
+                    if reply.send(Ok(stream)).is_err() {
+                        socket.abort();
+                        return false;
+                    }
+                }
+            }
+        }
+
+        // Device to stream
+        while socket.can_recv() {
+            let written =
+                socket.recv(
+                    |data| match Pin::new(&mut self.stream).poll_write(cx, data) {
+                        Poll::Ready(Ok(length)) => (length, Poll::Ready(Ok(length))),
+                        other => (0, other),
+                    },
+                );
+            match written {
+                Ok(Poll::Ready(Ok(length))) if length > 0 => *progress = true,
+                Ok(Poll::Ready(Err(_))) => {
+                    socket.abort();
+                    return false;
+                }
+                _ => break,
+            }
+        }
+
+        // Stream to device
+        while !self.write_closed && socket.can_send() {
+            let read = socket.send(|buffer| {
+                let mut buffer = ReadBuf::new(buffer);
+                match Pin::new(&mut self.stream).poll_read(cx, &mut buffer) {
+                    Poll::Ready(Ok(())) => {
+                        let length = buffer.filled().len();
+                        (length, Poll::Ready(Ok(length)))
+                    }
+                    Poll::Ready(Err(e)) => (0, Poll::Ready(Err(e))),
+                    Poll::Pending => (0, Poll::Pending),
+                }
+            });
+            match read {
+                Ok(Poll::Ready(Ok(0))) => {
+                    socket.close();
+                    self.write_closed = true;
+                    *progress = true;
+                }
+                Ok(Poll::Ready(Ok(_))) => *progress = true,
+                Ok(Poll::Ready(Err(_))) => {
+                    socket.abort();
+                    return false;
+                }
+                _ => break,
+            }
+        }
+
+        // Device done sending and everything passed on
+        if !self.read_closed && !socket.may_recv() && !socket.can_recv() {
+            if Pin::new(&mut self.stream).poll_shutdown(cx).is_ready() {
+                self.read_closed = true;
+                *progress = true;
+            }
+        }
+
+        !matches!(socket.state(), tcp::State::Closed | tcp::State::TimeWait)
+    }
+}
+
+/// Packets between the tunnel and smoltcp.
+struct PacketQueue {
+    received: VecDeque<Vec<u8>>,
+    sent: Vec<Vec<u8>>,
+    mtu: usize,
+}
+
+struct RxToken(Vec<u8>);
+
+struct TxToken<'a>(&'a mut Vec<Vec<u8>>);
+
+impl phy::RxToken for RxToken {
+    fn consume<R, F>(self, f: F) -> R
+    where
+        F: FnOnce(&[u8]) -> R,
+    {
+        f(&self.0)
+    }
+}
+
+impl phy::TxToken for TxToken<'_> {
+    fn consume<R, F>(self, len: usize, f: F) -> R
+    where
+        F: FnOnce(&mut [u8]) -> R,
+    {
+        let mut packet = vec![0u8; len];
+        let result = f(&mut packet);
+        self.0.push(packet);
+        result
+    }
+}
+
+impl phy::Device for PacketQueue {
+    type RxToken<'a> = RxToken;
+    type TxToken<'a> = TxToken<'a>;
+
+    fn receive(&mut self, _timestamp: Instant) -> Option<(RxToken, TxToken<'_>)> {
+        let packet = self.received.pop_front()?;
+        Some((RxToken(packet), TxToken(&mut self.sent)))
+    }
+
+    fn transmit(&mut self, _timestamp: Instant) -> Option<TxToken<'_>> {
+        Some(TxToken(&mut self.sent))
+    }
+
+    fn capabilities(&self) -> DeviceCapabilities {
+        let mut capabilities = DeviceCapabilities::default();
+        capabilities.medium = Medium::Ip;
+        capabilities.max_transmission_unit = self.mtu;
+        capabilities
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::{collections::VecDeque, net::Ipv6Addr, time::Duration};
+
+    use smoltcp::{
+        iface::{Config, Interface, SocketSet},
+        socket::tcp,
+        time::Instant,
+        wire::{HardwareAddress, IpAddress, IpCidr},
+    };
+    use tokio::{
+        io::{AsyncReadExt, AsyncWriteExt, DuplexStream},
+        sync::mpsc,
+    };
+
+    use super::{PacketQueue, UserspaceStack};
+    use crate::tunnel::{errors::TunnelError, read_ipv6_packet};
+
+    const HOST: Ipv6Addr = Ipv6Addr::new(0xfd00, 0, 0, 0, 0, 0, 0, 1);
+    const DEVICE: Ipv6Addr = Ipv6Addr::new(0xfd00, 0, 0, 0, 0, 0, 0, 2);
+
+    /// Plays the device: echoes whatever is sent to `port` and closes when
+    /// the host does.
+    async fn echo_device(tunnel: DuplexStream, port: u16) {
+        let (mut reader, mut writer) = tokio::io::split(tunnel);
+        // Packets are read in their own task, as a read cut short by the
+        // timer below would lose the start of a packet.
+        let (packets_tx, mut packets) = mpsc::unbounded_channel();
+        tokio::spawn(async move {
+            while let Ok(packet) = read_ipv6_packet(&mut reader, 1500).await {
+                if packets_tx.send(packet).is_err() {
+                    break;
+                }
+            }
+        });
+        let mut device = PacketQueue {
+            received: VecDeque::new(),
+            sent: Vec::new(),
+            mtu: 1500,
+        };
+        let mut iface = Interface::new(
+            Config::new(HardwareAddress::Ip),
+            &mut device,
+            Instant::now(),
+        );
+        iface.update_ip_addrs(|addresses| {
+            let _ = addresses.push(IpCidr::new(IpAddress::Ipv6(DEVICE), 64));
+        });
+        let mut socket = tcp::Socket::new(
+            tcp::SocketBuffer::new(vec![0; 4096]),
+            tcp::SocketBuffer::new(vec![0; 4096]),
+        );
+        socket.listen(port).unwrap();
+        let mut sockets = SocketSet::new(Vec::new());
+        let handle = sockets.add(socket);
+
+        loop {
+            iface.poll(Instant::now(), &mut device, &mut sockets);
+            let socket = sockets.get_mut::<tcp::Socket>(handle);
+            if socket.can_recv() {
+                let data = socket.recv(|data| (data.len(), data.to_vec())).unwrap();
+                socket.send_slice(&data).unwrap();
+            } else if socket.state() == tcp::State::CloseWait {
+                socket.close();
+            }
+            iface.poll(Instant::now(), &mut device, &mut sockets);
+            for packet in device.sent.drain(..) {
+                writer.write_all(&packet).await.unwrap();
+            }
+            tokio::select! {
+                packet = packets.recv() => match packet {
+                    Some(packet) => device.received.push_back(packet),
+                    None => break,
+                },
+                _ = tokio::time::sleep(Duration::from_millis(10)) => {}
+            }
+        }
+    }
+
+    #[tokio::test]
+    async fn test_userspace_stack() {
+        let (host_end, device_end) = tokio::io::duplex(1 << 16);
+        let device = tokio::spawn(echo_device(device_end, 1234));
+
+        let mut stack = UserspaceStack::new(HOST, 1500);
+        assert!(matches!(
+            stack.connect(DEVICE, 1234).await,
+            Err(TunnelError::Closed)
+        ));
+        let (reader, writer) = tokio::io::split(Box::new(host_end) as _);
+        let _handles = stack.on(reader, writer).await;
+
+        let mut stream = stack.connect(DEVICE, 1234).await.unwrap();
+        stream.write_all(b"hello").await.unwrap();
+        let mut reply = [0u8; 5];
+        stream.read_exact(&mut reply).await.unwrap();
+        assert_eq!(&reply, b"hello");
+
+        stream.shutdown().await.unwrap();
+        let mut rest = Vec::new();
+        stream.read_to_end(&mut rest).await.unwrap();
+        assert!(rest.is_empty());
+
+        let refused = stack.connect(DEVICE, 4321).await;
+        assert!(matches!(
+            refused,
+            Err(TunnelError::Io(e)) if e.kind() == std::io::ErrorKind::ConnectionRefused
+        ));
+
+        stack.terminate();
+        device.abort();
+    }
+
+    #[tokio::test]
+    async fn test_userspace_connect_timeout() {
+        // The device end is kept open but never answers.
+        let (host_end, _device_end) = tokio::io::duplex(1 << 16);
+        let mut stack = UserspaceStack::new(HOST, 1500);
+        stack.socket_timeout = Duration::from_millis(300);
+        let (reader, writer) = tokio::io::split(Box::new(host_end) as _);
+        let _handles = stack.on(reader, writer).await;
+
+        assert!(matches!(
+            stack.connect(DEVICE, 1234).await,
+            Err(TunnelError::Io(e)) if e.kind() == std::io::ErrorKind::TimedOut
+        ));
+        stack.terminate();
+    }
+}
@@ -0,0 +1,117 @@
+use std::{
+    net::Ipv6Addr, os::windows::process::CommandExt, path::Path, process::Command, sync::Arc,
+    thread, time::Duration,
+};
+
+use wintun::Session;
+
+use super::{errors::TunnelError, TunBackend, PREFIX_LENGTH};
+
+/// Name of the Wintun adapter.
+const ADAPTER_NAME: &str = "wintun";
+/// Keeps `netsh` from opening a console window.
+const CREATE_NO_WINDOW: u32 = 0x08000000;
+
+/// A Wintun adapter, configured with `netsh`. Needs `wintun.dll` and
+/// administrator rights.
+pub struct WintunBackend {
+    session: Arc<Session>,
+}
+
+impl WintunBackend {
+    /// Checks that the Wintun driver at `wintun_path` can be loaded.
+    pub fn check(wintun_path: &Path) -> Result<(), TunnelError> {
+        load(wintun_path).map(|_| ())
+    }
+
+    /// Loads the Wintun driver at `wintun_path` and creates the adapter.
+    pub fn create(wintun_path: &Path) -> Result<Self, TunnelError> {
+        let wintun = load(wintun_path)?;
+        let adapter =
+            wintun::Adapter::create(&wintun, ADAPTER_NAME, "smt", None).map_err(backend_error)?;
+        let session = adapter
+            .start_session(wintun::MAX_RING_CAPACITY)
+            .map_err(backend_error)?;
+        Ok(WintunBackend {
+            session: Arc::new(session),
+        })
+    }
+}
+
+impl TunBackend for WintunBackend {
+    fn set_address(&self, address: Ipv6Addr) -> Result<(), TunnelError> {
+        netsh(&[
+            "interface",
+            "ipv6",
+            "set",
+            "address",
+            &format!("interface=\"{}\"", ADAPTER_NAME),
+            &format!("address={}/{}", address, PREFIX_LENGTH),
+        ])?;
+        // Windows runs duplicate address detection before the address is
+        // usable.
+        thread::sleep(Duration::from_millis(2000));
+        Ok(())
+    }
+
+    fn set_mtu(&self, mtu: u32) -> Result<(), TunnelError> {
+        netsh(&[
+            "interface",
+            "ipv6",
+            "set",
+            "subinterface",
+            &format!("interface=\"{}\"", ADAPTER_NAME),
+            &format!("mtu={}", mtu),
+        ])
+    }
+
+    fn read_packet(&self) -> Result<Vec<u8>, TunnelError> {
+        match self.session.receive_blocking() {
+            Ok(packet) => Ok(packet.bytes().to_vec()),
+            Err(wintun::Error::ShuttingDown) => Err(TunnelError::Closed),
+            Err(e) => Err(backend_error(e)),
+        }
+    }
+
+    fn write_packet(&self, packet: &[u8]) -> Result<(), TunnelError> {
+        let length = u16::try_from(packet.len()).map_err(|_| TunnelError::FrameTooLarge {
+            length: packet.len(),
+            max: u16::MAX as usize,
+        })?;
+        let mut send_packet = self
+            .session
+            .allocate_send_packet(length)
+            .map_err(backend_error)?;
+        send_packet.bytes_mut().copy_from_slice(packet);
+        self.session.send_packet(send_packet);
+        Ok(())
+    }
+
+    fn shutdown(&self) {
+        let _ = self.session.shutdown();
+    }
+}
+
+fn load(wintun_path: &Path) -> Result<wintun::Wintun, TunnelError> {
+    unsafe { wintun::load_from_path(wintun_path) }
+        .map_err(|e| TunnelError::Backend(format!("{}: {}", wintun_path.display(), e)))
+}
+
+fn backend_error(error: wintun::Error) -> TunnelError {
+    TunnelError::Backend(error.to_string())
+}
+
+fn netsh(args: &[&str]) -> Result<(), TunnelError> {
+    let output = Command::new("netsh")
+        .args(args)
+        .creation_flags(CREATE_NO_WINDOW)
+        .output()?;
+    if !output.status.success() {
+        return Err(TunnelError::Backend(format!(
+            "netsh {}: {}",
+            args.join(" "),
+            String::from_utf8_lossy(&output.stdout).trim()
+        )));
+    }
+    Ok(())
+}
@@ -1,48 +1,118 @@
 // #fix
+pub mod device_info;
+mod encryption;
 pub mod errors;
+pub mod listen;
 pub mod message;
+pub mod pair_record;
 mod ssl2;
-mod usbmuxsock;
+pub mod usbmuxsock;
 use byteorder::{BigEndian, LittleEndian};
-use errors::{MessageOperationError, UsbmuxOperationError};
+use device_info::{ConnectionType, DeviceInfo};
+use errors::{MessageOperationError, PairRecordError, UsbmuxOperationError};
+use listen::UsbmuxEvent;
 use message::{
-    LockdownMessage, UsbMuxPlist, UsbmuxMessage, UsbmuxMessageData, UsbmuxMessageHeader,
+    decode_plist, Buid, DeviceList, GetValueResponse, ListenMessage, LockdownPacket,
+    LockdownRequest, PairRecordData, PlistFormat, UsbmuxMessageHeader, UsbmuxPacket, UsbmuxRequest,
+    UsbmuxResult, DEFAULT_MAX_PLIST_FRAME_SIZE, DEFAULT_MAX_USBMUX_FRAME_SIZE, RESULT_BADCOMMAND,
+    RESULT_BADDEV, RESULT_BADVERSION, RESULT_CONNREFUSED, RESULT_OK, USBMUX_HEADER_SIZE,
     USBMUX_MSGTYPE, USBMUX_VERSION,
 };
-use tokio_rustls::client::TlsStream;
-
-use plist::{to_writer_xml, Value};
+use pair_record::{PairRecord, PairRecordStore, PrivateKey};
+use plist::Value;
+use serde::{de::DeserializeOwned, Serialize};
 use serde_json::json;
-use ssl2::ssl_wrap_socket;
+use ssl2::{ssl_handshake_only, ssl_wrap_socket};
 use std::{
     io::{Cursor, Read, Write},
+    net::IpAddr,
     result,
 };
 use usbmuxsock::UsbmuxSock;
+use zeroize::{Zeroize, Zeroizing};
 
 use tokio::{
-    io::{AsyncRead, AsyncReadExt, AsyncWriteExt},
+    io::{AsyncReadExt, AsyncWriteExt},
     net::TcpStream,
+    sync::mpsc,
 };
+use tokio_stream::{wrappers::ReceiverStream, Stream};
+
+use crate::lockdown::LOCKDOWN_PORT;
+use crate::transport::BoxedTransport;
+
+/// How connections to device ports are made.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum DeviceConnection {
+    /// Through usbmuxd's `Connect`, for USB and usbmuxd-managed Wi-Fi
+    /// devices.
+    Usbmux,
+    /// Straight to the device's address over TCP, without usbmuxd.
+    Direct(IpAddr),
+}
 
 pub struct UsbMuxClient {
-    pub sock: Option<UsbmuxSock>,
-    pub ssl_sock: Option<TlsStream<TcpStream>>,
+    /// The current connection: usbmuxd itself, or a device service once
+    /// `connect_to_service` succeeded, possibly wrapped in TLS.
+    pub sock: Option<BoxedTransport>,
+    /// How `connect_to_service` reaches the device.
+    pub connection: DeviceConnection,
+    /// Tag of the last usbmuxd request sent on `sock`.
+    tag: u32,
+    /// Format used to serialize outgoing plists. Replies are accepted in
+    /// either format.
+    pub plist_format: PlistFormat,
+    /// Largest usbmuxd reply accepted, header included.
+    pub max_usbmux_frame_size: usize,
+    /// Largest length-prefixed plist accepted from lockdownd or a service.
+    pub max_plist_frame_size: usize,
+    /// Where pair records are read from. `None` asks usbmuxd.
+    pub pair_record_store: Option<PairRecordStore>,
+    /// Only devices attached this way are selected. `None` takes any, USB
+    /// first when a device is attached both ways.
+    pub connection_type: Option<ConnectionType>,
 
     pub device_id: Option<u16>,
     pub device_serial: Option<String>,
     pub system_buid: Option<String>,
     pub host_id: Option<String>,
     pub host_cert: Option<Box<[u8]>>,
-    pub host_key: Option<Box<[u8]>>,
+    pub host_key: Option<PrivateKey>,
+    /// Certificate the device must present in TLS handshakes.
+    pub device_cert: Option<Box<[u8]>>,
+    /// Whether TLS handshakes require `device_cert`. Turning this off accepts
+    /// any certificate, letting whoever answers on the usbmuxd socket
+    /// impersonate the device.
+    pub pin_device_certificate: bool,
 }
 
 impl UsbMuxClient {
     pub async fn new() -> Result<Self, UsbmuxOperationError> {
         let sock = UsbmuxSock::new().await?;
-        Ok(UsbMuxClient {
-            sock: Some(sock),
-            ssl_sock: None,
+        Ok(UsbMuxClient::from_transport(Box::new(sock.sock)))
+    }
+
+    /// Creates a client running over an already connected transport.
+    pub fn from_transport(sock: BoxedTransport) -> Self {
+        UsbMuxClient::with_connection(Some(sock), DeviceConnection::Usbmux)
+    }
+
+    /// Creates a client for the device at `address`, reached over TCP
+    /// without usbmuxd. Nothing is connected until `connect_to_service`.
+    pub fn direct(address: IpAddr) -> Self {
+        UsbMuxClient::with_connection(None, DeviceConnection::Direct(address))
+    }
+
+    fn with_connection(sock: Option<BoxedTransport>, connection: DeviceConnection) -> Self {
+        UsbMuxClient {
+            sock,
+            connection,
+            tag: 0,
+            plist_format: PlistFormat::default(),
+            max_usbmux_frame_size: DEFAULT_MAX_USBMUX_FRAME_SIZE,
+            max_plist_frame_size: DEFAULT_MAX_PLIST_FRAME_SIZE,
+            pair_record_store: None,
+            connection_type: None,
 
             device_id: None,
             device_serial: None,
@@ -50,21 +120,22 @@ impl UsbMuxClient {
             host_id: None,
             host_cert: None,
             host_key: None,
-        })
+            device_cert: None,
+            pin_device_certificate: true,
+        }
     }
 
     pub async fn send_usbmux_message(
         &mut self,
-        msg: &UsbmuxMessage,
+        header: &UsbmuxMessageHeader,
+        request: &UsbmuxRequest,
     ) -> Result<(), MessageOperationError> {
-        let plist_data = Value::Dictionary(msg.data.to_plist());
-        let mut plist_msg: Vec<u8> = Vec::new();
-        to_writer_xml(&mut plist_msg, &plist_data)?;
+        let plist_msg = self.plist_format.encode(&UsbmuxPacket::new(request))?;
         let mut payload = Vec::new();
 
-        byteorder::WriteBytesExt::write_u32::<LittleEndian>(&mut payload, msg.header.version)?;
-        byteorder::WriteBytesExt::write_u32::<LittleEndian>(&mut payload, msg.header.message)?;
-        byteorder::WriteBytesExt::write_u32::<LittleEndian>(&mut payload, msg.header.tag)?;
+        byteorder::WriteBytesExt::write_u32::<LittleEndian>(&mut payload, header.version)?;
+        byteorder::WriteBytesExt::write_u32::<LittleEndian>(&mut payload, header.message)?;
+        byteorder::WriteBytesExt::write_u32::<LittleEndian>(&mut payload, header.tag)?;
 
         payload.extend_from_slice(&plist_msg);
 
@@ -76,274 +147,423 @@ impl UsbMuxClient {
         self.sock
             .as_mut()
             .ok_or(MessageOperationError::MissingStream)?
-            .sock
             .write_all(&request)
             .await?;
 
         Ok(())
     }
 
-    pub async fn read_usbmux_response(&mut self) -> Result<Value, MessageOperationError> {
+    pub async fn read_usbmux_response(
+        &mut self,
+    ) -> Result<(UsbmuxMessageHeader, Value), MessageOperationError> {
         let total_length = self
             .sock
             .as_mut()
             .ok_or(MessageOperationError::MissingStream)?
-            .sock
             .read_u32_le()
-            .await?;
+            .await? as usize;
 
-        if total_length < 4 {
+        if total_length < USBMUX_HEADER_SIZE {
             return Err(MessageOperationError::ResponseError);
         }
+        if total_length > self.max_usbmux_frame_size {
+            return Err(MessageOperationError::FrameTooLarge {
+                length: total_length,
+                max: self.max_usbmux_frame_size,
+            });
+        }
 
-        let payload_length = total_length - 4;
-        let mut response_payload = vec![0u8; payload_length as usize];
+        let mut response_payload = vec![0u8; total_length - 4];
         self.sock
             .as_mut()
             .ok_or(MessageOperationError::MissingStream)?
-            .sock
             .read_exact(&mut response_payload)
             .await?;
         let mut cursor = Cursor::new(response_payload);
-        let _resp_version = byteorder::ReadBytesExt::read_u32::<LittleEndian>(&mut cursor)?;
-        let _resp_message = byteorder::ReadBytesExt::read_u32::<LittleEndian>(&mut cursor)?;
-        let _resp_tag = byteorder::ReadBytesExt::read_u32::<LittleEndian>(&mut cursor)?;
+        let header = UsbmuxMessageHeader {
+            version: byteorder::ReadBytesExt::read_u32::<LittleEndian>(&mut cursor)?,
+            message: byteorder::ReadBytesExt::read_u32::<LittleEndian>(&mut cursor)?,
+            tag: byteorder::ReadBytesExt::read_u32::<LittleEndian>(&mut cursor)?,
+        };
         let mut plist_bytes = Vec::new();
         std::io::Read::read_to_end(&mut cursor, &mut plist_bytes)?;
-        let plist_val = plist::Value::from_reader_xml(plist_bytes.as_slice())?;
+        let plist_val = decode_plist(&plist_bytes)?;
 
-        Ok(plist_val)
+        Ok((header, plist_val))
     }
 
-    pub async fn send_lockdown_message(
+    /// Sends `request` and reads its reply as `R`, checking that the reply
+    /// carries the same tag as the request.
+    pub async fn usbmux_request<R: DeserializeOwned>(
         &mut self,
-        msg: &LockdownMessage,
-        ssl: bool,
-    ) -> Result<(), MessageOperationError> {
-        let dict = msg.to_plist();
+        request: &UsbmuxRequest,
+    ) -> Result<R, UsbmuxOperationError> {
+        if let DeviceConnection::Direct(_) = self.connection {
+            return Err(UsbmuxOperationError::NoUsbmuxd);
+        }
+        let header = UsbmuxMessageHeader {
+            version: USBMUX_VERSION,
+            message: USBMUX_MSGTYPE,
+            tag: self.next_tag(),
+        };
+        self.send_usbmux_message(&header, request).await?;
+        let (response_header, response) = self.read_usbmux_response().await?;
+        if response_header.tag != header.tag {
+            return Err(UsbmuxOperationError::TagMismatch {
+                expected: header.tag,
+                received: response_header.tag,
+            });
+        }
+        Ok(plist::from_value(&response)?)
+    }
 
-        let mut plist_msg: Vec<u8> = Vec::new();
-        to_writer_xml(&mut plist_msg, &Value::Dictionary(dict))?;
+    /// Returns the tag for the next usbmuxd request.
+    fn next_tag(&mut self) -> u32 {
+        self.tag = self.tag.wrapping_add(1);
+        self.tag
+    }
+
+    /// Writes a length-prefixed plist message, the framing used by lockdownd
+    /// and the services it starts.
+    pub async fn send_plist_message<T: Serialize>(
+        &mut self,
+        msg: &T,
+    ) -> Result<(), MessageOperationError> {
+        let plist_msg = self.plist_format.encode(msg)?;
 
         let total_length = plist_msg.len() as u32;
         let mut request = Vec::new();
         request.extend_from_slice(&total_length.to_be_bytes());
         request.extend_from_slice(&plist_msg);
 
-        if ssl {
-            let sock = self
-                .ssl_sock
-                .as_mut()
-                .ok_or(MessageOperationError::MissingStream)?;
-            sock.write_all(&request).await?;
-        } else {
-            self.sock
-                .as_mut()
-                .ok_or(MessageOperationError::MissingStream)?
-                .sock
-                .write_all(&request)
-                .await?;
-        }
+        self.sock
+            .as_mut()
+            .ok_or(MessageOperationError::MissingStream)?
+            .write_all(&request)
+            .await?;
 
         Ok(())
     }
 
-    pub async fn read_lockdown_response(
-        &mut self,
-        ssl: bool,
-    ) -> Result<Value, MessageOperationError> {
+    /// Reads a length-prefixed plist message.
+    pub async fn read_plist_message(&mut self) -> Result<Value, MessageOperationError> {
+        let max = self.max_plist_frame_size;
         let mut length_data = [0u8; 4];
-        let sock: &mut (dyn AsyncRead + Unpin + Send) = if ssl {
-            self.ssl_sock
-                .as_mut()
-                .ok_or(MessageOperationError::MissingStream)?
-        } else {
-            &mut self
-                .sock
-                .as_mut()
-                .ok_or(MessageOperationError::MissingStream)?
-                .sock
-        };
+        let sock = self
+            .sock
+            .as_mut()
+            .ok_or(MessageOperationError::MissingStream)?;
 
         sock.read_exact(&mut length_data).await?;
 
         let payload_len = u32::from_be_bytes(length_data) as usize;
+        if payload_len > max {
+            return Err(MessageOperationError::FrameTooLarge {
+                length: payload_len,
+                max,
+            });
+        }
 
         let mut payload = vec![0u8; payload_len];
 
         sock.read_exact(&mut payload).await?;
 
-        let value = Value::from_reader_xml(&payload[..])?;
+        let value = decode_plist(&payload)?;
         Ok(value)
     }
 
-    pub async fn list_devices(&mut self) -> Result<(), UsbmuxOperationError> {
-        let msg = UsbmuxMessage {
-            header: UsbmuxMessageHeader {
-                version: USBMUX_VERSION,
-                message: USBMUX_MSGTYPE,
-                tag: 1,
-            },
-            data: UsbmuxMessageData {
-                MessageType: "ListDevices".to_string(),
-                PairRecordID: None,
-                ClientVersionString: "usbmuxd-client".to_string(),
-                ProgName: "client".to_string(),
-                kLibUSBMuxVersion: 3,
-                DeviceID: None,
-                PortNumber: None,
-            },
-        };
-        self.send_usbmux_message(&msg).await?;
-        let plist_val = self.read_usbmux_response().await?;
-        self.device_serial = match &plist_val {
-            Value::Dictionary(dict) => dict
-                .get("DeviceList")
-                .and_then(|dl| dl.as_array())
-                .and_then(|arr| arr.first())
-                .and_then(|device| device.as_dictionary())
-                .and_then(|info| {
-                    self.device_id = info
-                        .get("DeviceID")
-                        .and_then(|val| val.as_unsigned_integer())
-                        .and_then(|num| u16::try_from(num).ok());
-                    info.get("Properties")
-                })
-                .and_then(|props| props.as_dictionary())
-                .and_then(|props| props.get("SerialNumber"))
-                .and_then(|serial| serial.as_string())
-                .map(|s| s.to_string()),
-            _ => panic!("Unexpected response format"),
-        };
+    pub async fn send_lockdown_message(
+        &mut self,
+        request: &LockdownRequest,
+    ) -> Result<(), MessageOperationError> {
+        self.send_plist_message(&LockdownPacket::new(request)).await
+    }
 
-        Ok(())
+    pub async fn read_lockdown_response<R: DeserializeOwned>(
+        &mut self,
+    ) -> Result<R, MessageOperationError> {
+        let value = self.read_plist_message().await?;
+        Ok(plist::from_value(&value)?)
     }
 
-    pub async fn get_device_pair_record(&mut self) -> Result<(), UsbmuxOperationError> {
-        self.list_devices().await?;
-
-        let msg = UsbmuxMessage {
-            header: UsbmuxMessageHeader {
-                version: USBMUX_VERSION,
-                message: USBMUX_MSGTYPE,
-                tag: 1,
-            },
-            data: UsbmuxMessageData {
-                MessageType: "ReadPairRecord".to_string(),
-                PairRecordID: self.device_serial.clone(),
-                ClientVersionString: "usbmuxd-client".to_string(),
-                ProgName: "client".to_string(),
-                kLibUSBMuxVersion: 3,
-                DeviceID: None,
-                PortNumber: None,
-            },
+    /// Sends a lockdown request and reads its reply as `R`.
+    pub async fn lockdown_request<R: DeserializeOwned>(
+        &mut self,
+        request: &LockdownRequest,
+    ) -> Result<R, UsbmuxOperationError> {
+        self.send_lockdown_message(request).await?;
+        Ok(self.read_lockdown_response().await?)
+    }
+
+    pub async fn list_devices(&mut self) -> Result<Vec<DeviceInfo>, UsbmuxOperationError> {
+        let response: DeviceList = self.usbmux_request(&UsbmuxRequest::ListDevices).await?;
+
+        Ok(response
+            .device_list
+            .into_iter()
+            .map(DeviceInfo::from)
+            .collect())
+    }
+
+    /// Selects the device the client will talk to.
+    ///
+    /// When `udid` is `None` the first attached device is used, otherwise the
+    /// device whose serial number matches `udid`. Only devices attached as
+    /// `connection_type` are considered; without one, a device reachable over
+    /// both USB and Wi-Fi is used over USB.
+    ///
+    /// Direct clients have a single device; its UDID is asked from lockdownd
+    /// when `udid` is `None`.
+    pub async fn select_device(
+        &mut self,
+        udid: Option<&str>,
+    ) -> Result<DeviceInfo, UsbmuxOperationError> {
+        if let DeviceConnection::Direct(address) = self.connection {
+            let serial_number = match udid {
+                Some(udid) => udid.to_string(),
+                None => query_udid(address).await?,
+            };
+            let device = DeviceInfo {
+                device_id: 0,
+                serial_number,
+                connection_type: ConnectionType::Network,
+                product_id: None,
+                location_id: None,
+                connection_speed: None,
+            };
+            self.use_device(&device);
+            return Ok(device);
+        }
+
+        let mut devices: Vec<DeviceInfo> = self
+            .list_devices()
+            .await?
+            .into_iter()
+            .filter(|device| match &self.connection_type {
+                Some(connection_type) => device.connection_type == *connection_type,
+                None => true,
+            })
+            .collect();
+        // Stable, so the order usbmuxd reported is kept otherwise.
+        devices.sort_by_key(|device| device.connection_type != ConnectionType::Usb);
+        let device = match udid {
+            Some(udid) => devices
+                .into_iter()
+                .find(|device| device.serial_number.eq_ignore_ascii_case(udid))
+                .ok_or_else(|| UsbmuxOperationError::DeviceNotFound(udid.to_string()))?,
+            None => devices
+                .into_iter()
+                .next()
+                .ok_or(UsbmuxOperationError::NoDevices)?,
         };
-        self.send_usbmux_message(&msg).await?;
-        let plist_val = self.read_usbmux_response().await?;
-
-        if let Value::Dictionary(dict) = plist_val {
-            if let Some(pair_record_data) = dict.get("PairRecordData").and_then(|v| v.as_data()) {
-                let decoded_data = plist::Value::from_reader_xml(pair_record_data)?;
-                if let Value::Dictionary(data_dict) = decoded_data {
-                    self.system_buid = data_dict
-                        .get("SystemBUID")
-                        .and_then(|buid| buid.as_string())
-                        .map(|s| s.to_string());
-                    self.host_id = data_dict
-                        .get("HostID")
-                        .and_then(|hostid| hostid.as_string())
-                        .map(|s| s.to_string());
-                    self.host_cert = data_dict
-                        .get("HostCertificate")
-                        .and_then(|cert| cert.as_data())
-                        .map(|d| d.to_owned().into_boxed_slice());
-                    self.host_key = data_dict
-                        .get("HostPrivateKey")
-                        .and_then(|cert| cert.as_data())
-                        .map(|d| d.to_owned().into_boxed_slice());
+
+        self.use_device(&device);
+        Ok(device)
+    }
+
+    /// Makes `device` the target of subsequent `Connect` and pair record
+    /// requests.
+    pub fn use_device(&mut self, device: &DeviceInfo) {
+        self.device_id = Some(device.device_id);
+        self.device_serial = Some(device.serial_number.clone());
+    }
+
+    /// Opens a dedicated usbmuxd connection in `Listen` mode.
+    ///
+    /// The returned stream yields an event every time a device is attached,
+    /// detached or paired. usbmuxd first reports every device that is already
+    /// attached. The connection is closed when the stream is dropped.
+    pub async fn listen(
+    ) -> Result<impl Stream<Item = Result<UsbmuxEvent, UsbmuxOperationError>>, UsbmuxOperationError>
+    {
+        let mut client = UsbMuxClient::new().await?;
+        let response = client.usbmux_request(&UsbmuxRequest::Listen).await?;
+        check_usbmux_result(&response)?;
+
+        let (event_tx, event_rx) = mpsc::channel(16);
+        tokio::spawn(async move {
+            loop {
+                let event = tokio::select! {
+                    response = client.read_usbmux_response() => match response {
+                        Ok((_, value)) => match plist::from_value::<ListenMessage>(&value) {
+                            Ok(message) => Ok(UsbmuxEvent::from(message)),
+                            Err(_) => continue,
+                        },
+                        Err(error) => Err(UsbmuxOperationError::from(error)),
+                    },
+                    _ = event_tx.closed() => break,
+                };
+                let is_error = event.is_err();
+                if event_tx.send(event).await.is_err() || is_error {
+                    break;
                 }
             }
-            Ok(())
-        } else {
-            Err(UsbmuxOperationError::ParseError)
+        });
+
+        Ok(ReceiverStream::new(event_rx))
+    }
+
+    /// Loads the selected device's pair record and uses its host identity
+    /// for the lockdown session.
+    pub async fn get_device_pair_record(&mut self) -> Result<(), UsbmuxOperationError> {
+        let record = self.read_device_pair_record().await?;
+        self.use_pair_record(&record);
+        Ok(())
+    }
+
+    /// The store pair records are kept in: `pair_record_store`, or the
+    /// system lockdown directory for direct connections. `None` means
+    /// usbmuxd's store.
+    fn record_store(&self) -> Option<PairRecordStore> {
+        match (&self.pair_record_store, &self.connection) {
+            (Some(store), _) => Some(store.clone()),
+            (None, DeviceConnection::Direct(_)) => Some(PairRecordStore::system()),
+            (None, DeviceConnection::Usbmux) => None,
         }
     }
 
-    pub async fn start_lockdown_session(&mut self) -> Result<(), UsbmuxOperationError> {
-        let msg = LockdownMessage {
-            Label: Some("client".to_string()),
-            Request: Some("StartSession".to_string()),
-            Service: None,
-            HostID: Some(
-                self.host_id
-                    .clone()
-                    .ok_or_else(|| UsbmuxOperationError::MissingArguments("HostID"))?
-                    .to_uppercase(),
-            ),
-            SystemBUID: Some(
-                self.system_buid
-                    .clone()
-                    .ok_or_else(|| UsbmuxOperationError::MissingArguments("SystemBUID"))?,
-            ),
-            action: None,
-            Domain: None,
-            Key: None,
+    /// Reads the selected device's pair record from `pair_record_store`, or
+    /// from usbmuxd when no store is set.
+    pub async fn read_device_pair_record(&mut self) -> Result<PairRecord, UsbmuxOperationError> {
+        let serial = self
+            .device_serial
+            .clone()
+            .ok_or_else(|| UsbmuxOperationError::MissingArguments("SerialNumber"))?;
+
+        if let Some(store) = self.record_store() {
+            return Ok(store.read(&serial).await?);
+        }
+
+        let response: PairRecordData = match self
+            .usbmux_request(&UsbmuxRequest::ReadPairRecord {
+                pair_record_id: serial.clone(),
+            })
+            .await
+        {
+            Ok(response) => response,
+            // usbmuxd answers ENOENT, which shares its code with BadDevice
+            Err(UsbmuxOperationError::BadDevice) => {
+                return Err(PairRecordError::NotFound(serial).into())
+            }
+            Err(error) => return Err(error),
         };
+        Ok(PairRecord::from_bytes(response.pair_record_data.as_ref())?)
+    }
 
-        self.send_lockdown_message(&msg, false).await?;
-        let response = self.read_lockdown_response(false).await?;
-        Ok(())
+    /// Stores `record` as the selected device's pair record, in
+    /// `pair_record_store` or in usbmuxd's store when no store is set.
+    pub async fn save_device_pair_record(
+        &mut self,
+        record: &PairRecord,
+    ) -> Result<(), UsbmuxOperationError> {
+        let serial = self
+            .device_serial
+            .clone()
+            .ok_or_else(|| UsbmuxOperationError::MissingArguments("SerialNumber"))?;
+
+        if let Some(store) = self.record_store() {
+            return Ok(store.save(&serial, record).await?);
+        }
+
+        let response: UsbmuxResult = self
+            .usbmux_request(&UsbmuxRequest::SavePairRecord {
+                pair_record_id: serial,
+                pair_record_data: record.to_bytes()?.into(),
+                device_id: self.device_id.unwrap_or(0),
+            })
+            .await?;
+        check_usbmux_result(&response)
     }
 
-    pub async fn connect_to_lockdown(&mut self) -> Result<(), UsbmuxOperationError> {
-        self.connect_to_service(62078).await?;
-        Ok(())
+    /// Deletes the selected device's pair record, from `pair_record_store` or
+    /// from usbmuxd's store when no store is set.
+    pub async fn delete_device_pair_record(&mut self) -> Result<(), UsbmuxOperationError> {
+        let serial = self
+            .device_serial
+            .clone()
+            .ok_or_else(|| UsbmuxOperationError::MissingArguments("SerialNumber"))?;
+
+        if let Some(store) = self.record_store() {
+            return Ok(store.delete(&serial).await?);
+        }
+
+        let response: UsbmuxResult = self
+            .usbmux_request(&UsbmuxRequest::DeletePairRecord {
+                pair_record_id: serial,
+            })
+            .await?;
+        check_usbmux_result(&response)
+    }
+
+    /// Uses the host identity in `record` for subsequent lockdown requests.
+    pub fn use_pair_record(&mut self, record: &PairRecord) {
+        self.system_buid = Some(record.system_buid.clone());
+        self.host_id = Some(record.host_id.clone());
+        self.host_cert = Some(record.host_certificate.as_ref().into());
+        self.host_key = Some(record.host_private_key.clone());
+        self.device_cert = record
+            .device_certificate
+            .as_ref()
+            .map(|cert| cert.as_ref().into());
+    }
+
+    /// Reads the host's `SystemBUID` from usbmuxd.
+    pub async fn read_buid(&mut self) -> Result<String, UsbmuxOperationError> {
+        let response: Buid = self.usbmux_request(&UsbmuxRequest::ReadBuid).await?;
+        Ok(response.buid)
     }
 
     pub async fn connect_to_service(
         &mut self,
         service_port: u16,
     ) -> Result<(), UsbmuxOperationError> {
-        let msg = UsbmuxMessage {
-            header: UsbmuxMessageHeader {
-                version: USBMUX_VERSION,
-                message: USBMUX_MSGTYPE,
-                tag: 1,
-            },
-            data: UsbmuxMessageData {
-                MessageType: "Connect".to_string(),
-                PairRecordID: None,
-                ClientVersionString: "usbmuxd-client".to_string(),
-                ProgName: "client".to_string(),
-                kLibUSBMuxVersion: 3,
-                DeviceID: self.device_id,
-                PortNumber: Some(service_port.to_be()),
-            },
+        if let DeviceConnection::Direct(address) = self.connection {
+            let sock = TcpStream::connect((address, service_port)).await?;
+            self.sock = Some(Box::new(sock));
+            return Ok(());
+        }
+
+        let request = UsbmuxRequest::Connect {
+            device_id: self
+                .device_id
+                .ok_or_else(|| UsbmuxOperationError::MissingArguments("DeviceID"))?,
+            port_number: service_port.to_be(),
         };
 
-        self.send_usbmux_message(&msg).await?;
-        let response = self.read_usbmux_response().await?;
+        let response = self.usbmux_request(&request).await?;
+        check_usbmux_result(&response)?;
         Ok(())
     }
 
-    pub async fn ssl_lockdown_request(
-        &mut self,
-        msg: LockdownMessage,
-    ) -> Result<u16, UsbmuxOperationError> {
-        self.try_ssl_handshake().await?;
-
-        self.send_lockdown_message(&msg, true).await?;
-        let response = self.read_lockdown_response(true).await?;
-        let port = response
-            .as_dictionary()
-            .and_then(|dict| dict.get("Port"))
-            .and_then(|port| port.as_unsigned_integer())
-            .and_then(|unsigned| Some(unsigned as u16))
-            .ok_or_else(|| UsbmuxOperationError::ParseError)?;
-        Ok(port)
+    /// Opens a new usbmuxd connection for the same device, carrying over the
+    /// host identity and settings of this client. Direct clients get a new
+    /// unconnected client instead.
+    pub async fn reconnect(&self) -> Result<UsbMuxClient, UsbmuxOperationError> {
+        let mut client = match self.connection {
+            DeviceConnection::Usbmux => UsbMuxClient::new().await?,
+            DeviceConnection::Direct(address) => UsbMuxClient::direct(address),
+        };
+        client.plist_format = self.plist_format;
+        client.max_usbmux_frame_size = self.max_usbmux_frame_size;
+        client.max_plist_frame_size = self.max_plist_frame_size;
+        client.pair_record_store = self.pair_record_store.clone();
+        client.connection_type = self.connection_type.clone();
+        client.device_id = self.device_id;
+        client.device_serial = self.device_serial.clone();
+        client.system_buid = self.system_buid.clone();
+        client.host_id = self.host_id.clone();
+        client.host_cert = self.host_cert.clone();
+        client.host_key = self.host_key.clone();
+        client.device_cert = self.device_cert.clone();
+        client.pin_device_certificate = self.pin_device_certificate;
+        Ok(client)
     }
 
-    pub async fn try_ssl_handshake(&mut self) -> Result<(), UsbmuxOperationError> {
+    /// Returns the host certificate and key, and the device certificate to
+    /// pin unless pinning is turned off.
+    fn tls_identity(
+        &self,
+    ) -> Result<(Vec<u8>, Zeroizing<Vec<u8>>, Option<Vec<u8>>), UsbmuxOperationError> {
         let cert = self
             .host_cert
             .as_ref()
@@ -354,60 +574,56 @@ impl UsbMuxClient {
             .as_ref()
             .ok_or_else(|| UsbmuxOperationError::MissingArguments("key"))?;
 
-        let ssl_sock = ssl_wrap_socket(
-            self.sock
-                .take()
-                .ok_or_else(|| UsbmuxOperationError::Error("missing ssl socket".to_string()))?
-                .sock,
-            &cert,
-            &key,
-        )
-        .await?;
+        let device_cert = if self.pin_device_certificate {
+            let device_cert = self
+                .device_cert
+                .as_ref()
+                .ok_or_else(|| UsbmuxOperationError::MissingArguments("DeviceCertificate"))?;
+            Some(device_cert.to_vec())
+        } else {
+            None
+        };
+
+        Ok((
+            cert.to_vec(),
+            Zeroizing::new(key.as_ref().to_vec()),
+            device_cert,
+        ))
+    }
+
+    fn take_sock(&mut self) -> Result<BoxedTransport, UsbmuxOperationError> {
+        self.sock
+            .take()
+            .ok_or_else(|| UsbmuxOperationError::Error("missing ssl socket".to_string()))
+    }
+
+    pub async fn try_ssl_handshake(&mut self) -> Result<(), UsbmuxOperationError> {
+        let (cert, key, device_cert) = self.tls_identity()?;
+
+        let ssl_sock =
+            ssl_wrap_socket(self.take_sock()?, &cert, &key, device_cert.as_deref()).await?;
 
-        self.ssl_sock = Some(ssl_sock);
+        self.sock = Some(Box::new(ssl_sock));
 
         Ok(())
     }
 
-    pub async fn conncet_to_cdp(&mut self) -> Result<(), UsbmuxOperationError> {
-        let msg = LockdownMessage {
-            Label: Some("client".to_string()),
-            Request: Some("StartService".to_string()),
-            Service: Some("com.apple.internal.devicecompute.CoreDeviceProxy".to_string()),
-            HostID: None,
-            SystemBUID: None,
-            action: None,
-            Domain: None,
-            Key: None,
-        };
-        let port = self.ssl_lockdown_request(msg).await?;
-        let msg = LockdownMessage {
-            Label: None,
-            Request: Some("GetValue".to_string()),
-            Service: None,
-            HostID: None,
-            SystemBUID: None,
-            action: None,
-            Domain: Some("com.apple.security.mac.amfi".to_string()),
-            Key: Some("DeveloperModeStatus".to_string()),
-        };
-        self.send_lockdown_message(&msg, true).await?;
-        let response = self.read_lockdown_response(true).await?;
-        let dev_status = response
-            .as_dictionary()
-            .and_then(|dict| dict.get("Value"))
-            .and_then(|value| value.as_boolean())
-            .ok_or_else(|| UsbmuxOperationError::ParseError)?;
-        if (!dev_status) {
-            return Err(UsbmuxOperationError::Error(
-                "Developer mode disabled".to_string(),
-            ));
-        }
-        self.sock = Some(UsbmuxSock::new().await?);
-        self.connect_to_service(port).await?;
+    /// Authenticates with a TLS handshake, then keeps talking in plaintext on
+    /// the same connection, as legacy services expect.
+    pub async fn ssl_handshake_only(&mut self) -> Result<(), UsbmuxOperationError> {
+        let (cert, key, device_cert) = self.tls_identity()?;
+
+        let sock =
+            ssl_handshake_only(self.take_sock()?, &cert, &key, device_cert.as_deref()).await?;
+
+        self.sock = Some(sock);
+
         Ok(())
     }
 
+    /// Runs the CDTunnel handshake on a connection to the CoreDeviceProxy
+    /// service and returns the tunnel's client address, MTU, and the RSD
+    /// address and port.
     pub async fn try_cdp_handshake(
         &mut self,
     ) -> Result<(String, u32, String, u16), UsbmuxOperationError> {
@@ -427,10 +643,8 @@ impl UsbMuxClient {
             netmask: String,
         }
 
-        self.try_ssl_handshake().await?;
-
         let sock = self
-            .ssl_sock
+            .sock
             .as_mut()
             .ok_or(MessageOperationError::MissingStream)?;
 
@@ -455,7 +669,7 @@ impl UsbMuxClient {
         sock.read_exact(&mut header).await?;
 
         if &header[..magic.len()] != magic {
-            panic!("No magic bytes")
+            return Err(MessageOperationError::ResponseError.into());
         }
 
         let mut cursor = Cursor::new(&header[magic.len()..]);
@@ -473,34 +687,329 @@ impl UsbMuxClient {
             parsed.serverRSDPort as u16,
         ))
     }
+}
 
-    pub async fn connect_to_amfi(&mut self) -> Result<(), UsbmuxOperationError> {
-        let msg = LockdownMessage {
-            Label: Some("client".to_string()),
-            Request: Some("StartService".to_string()),
-            Service: Some("com.apple.amfi.lockdown".to_string()),
-            HostID: None,
-            SystemBUID: None,
-            action: None,
-            Domain: None,
-            Key: None,
-        };
-        let port = self.ssl_lockdown_request(msg).await?;
-        self.sock = Some(UsbmuxSock::new().await?);
-        self.connect_to_service(port).await?;
-        self.try_ssl_handshake().await?;
-        let msg = LockdownMessage {
-            Label: None,
-            Request: None,
-            Service: None,
-            HostID: None,
-            SystemBUID: None,
-            action: Some(0),
-            Domain: None,
-            Key: None,
-        };
-        self.send_lockdown_message(&msg, true).await?;
+impl Drop for UsbMuxClient {
+    /// Wipes the host certificate. The private key wipes itself.
+    fn drop(&mut self) {
+        self.host_cert.zeroize();
+    }
+}
 
-        Ok(())
+/// Asks lockdownd at `address` for the device's UDID, which it tells
+/// unpaired hosts too.
+async fn query_udid(address: IpAddr) -> Result<String, UsbmuxOperationError> {
+    let mut client = UsbMuxClient::direct(address);
+    client.connect_to_service(LOCKDOWN_PORT).await?;
+    let request = LockdownRequest::GetValue {
+        domain: None,
+        key: Some("UniqueDeviceID".to_string()),
+    };
+    let response: GetValueResponse<String> = client.lockdown_request(&request).await?;
+    if let Some(error) = response.error {
+        return Err(UsbmuxOperationError::Error(error));
+    }
+    response.value.ok_or(UsbmuxOperationError::ParseError)
+}
+
+/// Maps the `Number` of a usbmuxd `Result` reply to an error.
+fn check_usbmux_result(response: &UsbmuxResult) -> Result<(), UsbmuxOperationError> {
+    match response.number {
+        RESULT_OK => Ok(()),
+        RESULT_BADCOMMAND => Err(UsbmuxOperationError::BadCommand),
+        RESULT_BADDEV => Err(UsbmuxOperationError::BadDevice),
+        RESULT_CONNREFUSED => Err(UsbmuxOperationError::ConnectionRefused),
+        RESULT_BADVERSION => Err(UsbmuxOperationError::BadVersion),
+        other => Err(UsbmuxOperationError::UnknownResult(other)),
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use plist::{Dictionary, Value};
+    use tokio::io::{AsyncReadExt, AsyncWriteExt, DuplexStream};
+
+    use super::{
+        device_info::ConnectionType,
+        errors::{MessageOperationError, UsbmuxOperationError},
+        pair_record::PairRecord,
+        UsbMuxClient,
+    };
+
+    async fn read_request(server: &mut DuplexStream) -> Value {
+        let total_length = server.read_u32_le().await.unwrap();
+        let mut payload = vec![0u8; total_length as usize - 4];
+        server.read_exact(&mut payload).await.unwrap();
+        Value::from_reader_xml(&payload[12..]).unwrap()
+    }
+
+    async fn write_response(server: &mut DuplexStream, tag: u32, value: &Value) {
+        let mut plist_bytes = Vec::new();
+        plist::to_writer_xml(&mut plist_bytes, value).unwrap();
+        server
+            .write_u32_le(plist_bytes.len() as u32 + 16)
+            .await
+            .unwrap();
+        server.write_u32_le(1).await.unwrap();
+        server.write_u32_le(8).await.unwrap();
+        server.write_u32_le(tag).await.unwrap();
+        server.write_all(&plist_bytes).await.unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_list_devices() {
+        let (client_end, mut server) = tokio::io::duplex(4096);
+        let mut client = UsbMuxClient::from_transport(Box::new(client_end));
+
+        let usbmuxd = tokio::spawn(async move {
+            let request = read_request(&mut server).await;
+            assert_eq!(
+                request
+                    .as_dictionary()
+                    .and_then(|dict| dict.get("MessageType"))
+                    .and_then(|mt| mt.as_string()),
+                Some("ListDevices")
+            );
+
+            let mut props = Dictionary::new();
+            props.insert("SerialNumber".to_string(), Value::from("00008030-001A"));
+            props.insert("ConnectionType".to_string(), Value::from("USB"));
+            props.insert("ProductID".to_string(), Value::from(4776u64));
+            let mut device = Dictionary::new();
+            device.insert("DeviceID".to_string(), Value::from(7u64));
+            device.insert("Properties".to_string(), Value::Dictionary(props));
+            let mut response = Dictionary::new();
+            response.insert(
+                "DeviceList".to_string(),
+                Value::Array(vec![Value::Dictionary(device)]),
+            );
+            write_response(&mut server, 1, &Value::Dictionary(response)).await;
+        });
+
+        let devices = client.list_devices().await.unwrap();
+        usbmuxd.await.unwrap();
+
+        assert_eq!(devices.len(), 1);
+        assert_eq!(devices[0].device_id, 7);
+        assert_eq!(devices[0].serial_number, "00008030-001A");
+        assert_eq!(devices[0].product_id, Some(4776));
+    }
+
+    fn device_entry(device_id: u64, serial_number: &str, connection_type: &str) -> Value {
+        let mut props = Dictionary::new();
+        props.insert("SerialNumber".to_string(), Value::from(serial_number));
+        props.insert("ConnectionType".to_string(), Value::from(connection_type));
+        let mut device = Dictionary::new();
+        device.insert("DeviceID".to_string(), Value::from(device_id));
+        device.insert("Properties".to_string(), Value::Dictionary(props));
+        Value::Dictionary(device)
+    }
+
+    #[tokio::test]
+    async fn test_select_device_connection_type() {
+        let (client_end, mut server) = tokio::io::duplex(4096);
+        let mut client = UsbMuxClient::from_transport(Box::new(client_end));
+
+        let usbmuxd = tokio::spawn(async move {
+            for tag in 1..=2 {
+                read_request(&mut server).await;
+                let mut response = Dictionary::new();
+                response.insert(
+                    "DeviceList".to_string(),
+                    Value::Array(vec![
+                        device_entry(9, "00008030-001A", "Network"),
+                        device_entry(7, "00008030-001A", "USB"),
+                    ]),
+                );
+                write_response(&mut server, tag, &Value::Dictionary(response)).await;
+            }
+        });
+
+        let device = client.select_device(Some("00008030-001a")).await.unwrap();
+        assert_eq!(device.device_id, 7);
+
+        client.connection_type = Some(ConnectionType::Network);
+        let device = client.select_device(None).await.unwrap();
+        assert_eq!(device.device_id, 9);
+        assert_eq!(client.device_id, Some(9));
+        usbmuxd.await.unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_direct_connection() {
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let port = listener.local_addr().unwrap().port();
+        let mut client = UsbMuxClient::direct("127.0.0.1".parse().unwrap());
+        client.device_id = Some(0);
+
+        assert!(matches!(
+            client.list_devices().await,
+            Err(UsbmuxOperationError::NoUsbmuxd)
+        ));
+
+        let device = tokio::spawn(async move {
+            let (mut sock, _) = listener.accept().await.unwrap();
+            let length = sock.read_u32().await.unwrap();
+            let mut payload = vec![0u8; length as usize];
+            sock.read_exact(&mut payload).await.unwrap();
+            plist::from_bytes::<Dictionary>(&payload).unwrap()
+        });
+
+        let mut service = client.reconnect().await.unwrap();
+        assert!(service.sock.is_none());
+        service.connect_to_service(port).await.unwrap();
+        let mut message = Dictionary::new();
+        message.insert("Command".to_string(), Value::from("Polo"));
+        service.send_plist_message(&message).await.unwrap();
+        assert_eq!(device.await.unwrap(), message);
+    }
+
+    fn result_response(number: u64) -> Value {
+        let mut response = Dictionary::new();
+        response.insert("MessageType".to_string(), Value::from("Result"));
+        response.insert("Number".to_string(), Value::from(number));
+        Value::Dictionary(response)
+    }
+
+    #[tokio::test]
+    async fn test_connect_refused() {
+        let (client_end, mut server) = tokio::io::duplex(4096);
+        let mut client = UsbMuxClient::from_transport(Box::new(client_end));
+        client.device_id = Some(7);
+
+        let usbmuxd = tokio::spawn(async move {
+            read_request(&mut server).await;
+            write_response(&mut server, 1, &result_response(3)).await;
+        });
+
+        let result = client.connect_to_service(62078).await;
+        usbmuxd.await.unwrap();
+        assert!(matches!(
+            result,
+            Err(UsbmuxOperationError::ConnectionRefused)
+        ));
+    }
+
+    #[tokio::test]
+    async fn test_tag_mismatch() {
+        let (client_end, mut server) = tokio::io::duplex(4096);
+        let mut client = UsbMuxClient::from_transport(Box::new(client_end));
+        client.device_id = Some(7);
+
+        let usbmuxd = tokio::spawn(async move {
+            read_request(&mut server).await;
+            write_response(&mut server, 1, &result_response(0)).await;
+            read_request(&mut server).await;
+            write_response(&mut server, 1, &result_response(0)).await;
+        });
+
+        client.connect_to_service(62078).await.unwrap();
+        let result = client.connect_to_service(62078).await;
+        usbmuxd.await.unwrap();
+        assert!(matches!(
+            result,
+            Err(UsbmuxOperationError::TagMismatch {
+                expected: 2,
+                received: 1
+            })
+        ));
+    }
+
+    #[tokio::test]
+    async fn test_usbmux_frame_too_large() {
+        let (client_end, mut server) = tokio::io::duplex(4096);
+        let mut client = UsbMuxClient::from_transport(Box::new(client_end));
+
+        server.write_u32_le(u32::MAX).await.unwrap();
+        let result = client.read_usbmux_response().await;
+        assert!(matches!(
+            result,
+            Err(MessageOperationError::FrameTooLarge {
+                length: 0xffff_ffff,
+                ..
+            })
+        ));
+    }
+
+    #[tokio::test]
+    async fn test_usbmux_frame_too_short() {
+        let (client_end, mut server) = tokio::io::duplex(4096);
+        let mut client = UsbMuxClient::from_transport(Box::new(client_end));
+
+        server.write_u32_le(8).await.unwrap();
+        server.write_u32_le(1).await.unwrap();
+        let result = client.read_usbmux_response().await;
+        assert!(matches!(result, Err(MessageOperationError::ResponseError)));
+    }
+
+    #[tokio::test]
+    async fn test_plist_frame_limits() {
+        let (client_end, mut server) = tokio::io::duplex(4096);
+        let mut client = UsbMuxClient::from_transport(Box::new(client_end));
+        client.max_plist_frame_size = 64;
+
+        server.write_u32(65).await.unwrap();
+        let result = client.read_plist_message().await;
+        assert!(matches!(
+            result,
+            Err(MessageOperationError::FrameTooLarge {
+                length: 65,
+                max: 64
+            })
+        ));
+
+        // A frame within the limit that is not a plist is a parse error, and
+        // a truncated one an IO error.
+        server.write_u32(4).await.unwrap();
+        server.write_all(b"junk").await.unwrap();
+        let result = client.read_plist_message().await;
+        assert!(matches!(result, Err(MessageOperationError::Plist(_))));
+
+        server.write_u32(32).await.unwrap();
+        server.write_all(b"<?xml").await.unwrap();
+        drop(server);
+        let result = client.read_plist_message().await;
+        assert!(matches!(result, Err(MessageOperationError::Io(_))));
+    }
+
+    #[tokio::test]
+    async fn test_save_pair_record() {
+        let (client_end, mut server) = tokio::io::duplex(4096);
+        let mut client = UsbMuxClient::from_transport(Box::new(client_end));
+        client.device_id = Some(7);
+        client.device_serial = Some("00008030-001A".to_string());
+
+        let usbmuxd = tokio::spawn(async move {
+            let request = read_request(&mut server).await;
+            write_response(&mut server, 1, &result_response(0)).await;
+            request
+        });
+
+        let record = PairRecord {
+            host_id: "HOST".to_string(),
+            system_buid: "BUID".to_string(),
+            host_certificate: vec![1].into(),
+            host_private_key: vec![2].into(),
+            device_certificate: None,
+            root_certificate: None,
+            root_private_key: None,
+            escrow_bag: None,
+            wifi_mac_address: None,
+        };
+        client.save_device_pair_record(&record).await.unwrap();
+
+        let request = usbmuxd.await.unwrap();
+        let request = request.as_dictionary().unwrap();
+        assert_eq!(
+            request.get("MessageType").and_then(|v| v.as_string()),
+            Some("SavePairRecord")
+        );
+        assert_eq!(
+            request.get("PairRecordID").and_then(|v| v.as_string()),
+            Some("00008030-001A")
+        );
+        let data = request.get("PairRecordData").and_then(|v| v.as_data());
+        let saved = PairRecord::from_bytes(data.unwrap()).unwrap();
+        assert_eq!(saved.host_id, "HOST");
     }
 }
@@ -0,0 +1,50 @@
+use super::message::DeviceEntry;
+
+/// How a device is attached to usbmuxd.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum ConnectionType {
+    Usb,
+    Network,
+    Other(String),
+}
+
+impl From<&str> for ConnectionType {
+    fn from(value: &str) -> Self {
+        match value {
+            "USB" => ConnectionType::Usb,
+            "Network" => ConnectionType::Network,
+            other => ConnectionType::Other(other.to_string()),
+        }
+    }
+}
+
+/// A device entry as reported by usbmuxd's `ListDevices`.
+#[derive(Debug, Clone)]
+pub struct DeviceInfo {
+    /// usbmuxd handle used in `Connect` requests.
+    pub device_id: u16,
+    /// The device UDID.
+    pub serial_number: String,
+    pub connection_type: ConnectionType,
+    pub product_id: Option<u16>,
+    pub location_id: Option<u32>,
+    pub connection_speed: Option<u32>,
+}
+
+impl From<DeviceEntry> for DeviceInfo {
+    fn from(entry: DeviceEntry) -> Self {
+        let props = entry.properties;
+        DeviceInfo {
+            device_id: entry.device_id,
+            serial_number: props.serial_number,
+            connection_type: props
+                .connection_type
+                .as_deref()
+                .map(ConnectionType::from)
+                .unwrap_or(ConnectionType::Usb),
+            product_id: props.product_id,
+            location_id: props.location_id,
+            connection_speed: props.connection_speed,
+        }
+    }
+}
@@ -0,0 +1,151 @@
+use std::fmt;
+use std::num::NonZeroU32;
+
+use aws_lc_rs::aead::{Aad, LessSafeKey, Nonce, UnboundKey, AES_256_GCM, NONCE_LEN};
+use aws_lc_rs::{pbkdf2, rand};
+use zeroize::Zeroizing;
+
+use super::errors::PairRecordError;
+
+/// Marks an encrypted pair record file.
+const MAGIC: &[u8; 8] = b"RLSPAIR1";
+const SALT_LEN: usize = 16;
+const KEY_LEN: usize = 32;
+/// Length of the authenticated header: magic, iteration count and salt.
+const HEADER_LEN: usize = MAGIC.len() + 4 + SALT_LEN;
+/// PBKDF2-HMAC-SHA256 rounds for new files.
+const PBKDF2_ITERATIONS: u32 = 600_000;
+/// Files asking for more rounds are rejected rather than stalling the reader.
+const MAX_PBKDF2_ITERATIONS: u32 = 10_000_000;
+
+/// Passphrase protecting a pair record store. Wiped from memory when dropped
+/// and kept out of `Debug` output.
+#[derive(Clone, PartialEq, Eq)]
+pub struct Passphrase(Zeroizing<String>);
+
+impl Passphrase {
+    pub fn new(passphrase: impl Into<String>) -> Self {
+        Passphrase(Zeroizing::new(passphrase.into()))
+    }
+}
+
+impl fmt::Debug for Passphrase {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str("Passphrase(..)")
+    }
+}
+
+/// Whether `bytes` is an encrypted pair record.
+pub fn is_sealed(bytes: &[u8]) -> bool {
+    bytes.starts_with(MAGIC)
+}
+
+/// Encrypts `plaintext` with AES-256-GCM under a key derived from
+/// `passphrase` with PBKDF2 and a fresh salt. The header is authenticated
+/// along with the ciphertext.
+pub fn seal(passphrase: &Passphrase, plaintext: &[u8]) -> Result<Vec<u8>, PairRecordError> {
+    let mut salt = [0u8; SALT_LEN];
+    rand::fill(&mut salt)?;
+    let mut nonce = [0u8; NONCE_LEN];
+    rand::fill(&mut nonce)?;
+
+    let mut sealed = Vec::with_capacity(HEADER_LEN + NONCE_LEN + plaintext.len() + 16);
+    sealed.extend_from_slice(MAGIC);
+    sealed.extend_from_slice(&PBKDF2_ITERATIONS.to_be_bytes());
+    sealed.extend_from_slice(&salt);
+
+    let key = derive_key(passphrase, &salt, PBKDF2_ITERATIONS)?;
+    let mut in_out = plaintext.to_vec();
+    key.seal_in_place_append_tag(
+        Nonce::assume_unique_for_key(nonce),
+        Aad::from(&sealed[..HEADER_LEN]),
+        &mut in_out,
+    )?;
+
+    sealed.extend_from_slice(&nonce);
+    sealed.extend_from_slice(&in_out);
+    Ok(sealed)
+}
+
+/// Decrypts a file produced by [`seal`].
+///
+/// # Errors
+///
+/// Returns [`PairRecordError::WrongPassphrase`] if authentication fails,
+/// which also covers tampered files.
+pub fn open(passphrase: &Passphrase, sealed: &[u8]) -> Result<Zeroizing<Vec<u8>>, PairRecordError> {
+    if !is_sealed(sealed) || sealed.len() < HEADER_LEN + NONCE_LEN {
+        return Err(PairRecordError::Corrupted);
+    }
+    let (header, rest) = sealed.split_at(HEADER_LEN);
+    let (nonce, ciphertext) = rest.split_at(NONCE_LEN);
+
+    let (iterations, salt) = header[MAGIC.len()..].split_at(4);
+    let iterations =
+        u32::from_be_bytes([iterations[0], iterations[1], iterations[2], iterations[3]]);
+    if iterations > MAX_PBKDF2_ITERATIONS {
+        return Err(PairRecordError::Corrupted);
+    }
+
+    let key = derive_key(passphrase, salt, iterations)?;
+    let nonce = Nonce::try_assume_unique_for_key(nonce)?;
+    let mut in_out = Zeroizing::new(ciphertext.to_vec());
+    let plaintext_len = key
+        .open_in_place(nonce, Aad::from(header), &mut in_out)
+        .map_err(|_| PairRecordError::WrongPassphrase)?
+        .len();
+    in_out.truncate(plaintext_len);
+    Ok(in_out)
+}
+
+fn derive_key(
+    passphrase: &Passphrase,
+    salt: &[u8],
+    iterations: u32,
+) -> Result<LessSafeKey, PairRecordError> {
+    let iterations = NonZeroU32::new(iterations).ok_or(PairRecordError::Corrupted)?;
+    let mut key = Zeroizing::new([0u8; KEY_LEN]);
+    pbkdf2::derive(
+        pbkdf2::PBKDF2_HMAC_SHA256,
+        iterations,
+        salt,
+        passphrase.0.as_bytes(),
+        key.as_mut(),
+    );
+    Ok(LessSafeKey::new(UnboundKey::new(
+        &AES_256_GCM,
+        key.as_ref(),
+    )?))
+}
+
+#[cfg(test)]
+mod test {
+    use super::{is_sealed, open, seal, Passphrase};
+    use crate::usbmux::errors::PairRecordError;
+
+    #[test]
+    fn test_seal_open() {
+        let passphrase = Passphrase::new("correct horse");
+        let sealed = seal(&passphrase, b"<plist/>").unwrap();
+        assert!(is_sealed(&sealed));
+        assert!(!sealed.windows(8).any(|w| w == b"<plist/>"));
+
+        assert_eq!(open(&passphrase, &sealed).unwrap().as_slice(), b"<plist/>");
+        assert!(matches!(
+            open(&Passphrase::new("battery staple"), &sealed),
+            Err(PairRecordError::WrongPassphrase)
+        ));
+
+        let mut tampered = sealed.clone();
+        *tampered.last_mut().unwrap() ^= 1;
+        assert!(matches!(
+            open(&passphrase, &tampered),
+            Err(PairRecordError::WrongPassphrase)
+        ));
+        assert!(matches!(
+            open(&passphrase, &sealed[..20]),
+            Err(PairRecordError::Corrupted)
+        ));
+        assert_eq!(format!("{:?}", passphrase), "Passphrase(..)");
+    }
+}
@@ -15,6 +15,8 @@ pub enum MessageOperationError {
     MissingStream,
     #[error("Invalid or no response")]
     ResponseError,
+    #[error("Frame of {length} bytes exceeds the {max} byte limit")]
+    FrameTooLarge { length: usize, max: usize },
 }
 
 // USBMUX OPERATION ERROR
@@ -32,10 +34,30 @@ pub enum UsbmuxOperationError {
     JsonError(#[from] serde_json::Error),
     #[error("Ssl sock error: {0}")]
     SslError(#[from] SslError),
+    #[error("Pair record error: {0}")]
+    PairRecordError(#[from] PairRecordError),
     #[error("Missing arguments: {0}")]
     MissingArguments(&'static str),
     #[error("Parse error")]
     ParseError,
+    #[error("usbmuxd rejected the command")]
+    BadCommand,
+    #[error("usbmuxd does not know the device")]
+    BadDevice,
+    #[error("Device refused the connection")]
+    ConnectionRefused,
+    #[error("usbmuxd does not support the protocol version")]
+    BadVersion,
+    #[error("usbmuxd returned unknown result code {0}")]
+    UnknownResult(u64),
+    #[error("Reply tag {received} does not match request tag {expected}")]
+    TagMismatch { expected: u32, received: u32 },
+    #[error("usbmuxd is not available for direct connections")]
+    NoUsbmuxd,
+    #[error("No devices attached")]
+    NoDevices,
+    #[error("Device not found: {0}")]
+    DeviceNotFound(String),
     #[error("Error: {0}")]
     Error(String),
 }
@@ -47,6 +69,27 @@ pub enum UsbmuxSockError {
     Io(#[from] std::io::Error),
 }
 
+// PAIR RECORD ERROR
+#[derive(Debug, thiserror::Error)]
+pub enum PairRecordError {
+    #[error("IO error: {0}")]
+    Io(#[from] std::io::Error),
+    #[error("Plist error: {0}")]
+    Plist(#[from] plist::Error),
+    #[error("No pair record for {0}")]
+    NotFound(String),
+    #[error("{0} already exists")]
+    FileExists(String),
+    #[error("Pair record for {0} is encrypted, set a passphrase")]
+    Encrypted(String),
+    #[error("Wrong passphrase, or the pair record was modified")]
+    WrongPassphrase,
+    #[error("Encrypted pair record is corrupted")]
+    Corrupted,
+    #[error("Crypto error")]
+    Crypto(#[from] aws_lc_rs::error::Unspecified),
+}
+
 // SSL ERROR
 #[derive(Debug, thiserror::Error)]
 pub enum SslError {
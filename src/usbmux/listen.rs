@@ -0,0 +1,60 @@
+use super::device_info::DeviceInfo;
+use super::message::ListenMessage;
+
+/// A device notification delivered by usbmuxd while in `Listen` mode.
+#[derive(Debug, Clone)]
+pub enum UsbmuxEvent {
+    Attached(DeviceInfo),
+    Detached { device_id: u16 },
+    Paired { device_id: u16 },
+}
+
+impl From<ListenMessage> for UsbmuxEvent {
+    fn from(message: ListenMessage) -> Self {
+        match message {
+            ListenMessage::Attached(entry) => UsbmuxEvent::Attached(entry.into()),
+            ListenMessage::Detached { device_id } => UsbmuxEvent::Detached { device_id },
+            ListenMessage::Paired { device_id } => UsbmuxEvent::Paired { device_id },
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use plist::{Dictionary, Value};
+
+    use super::{ListenMessage, UsbmuxEvent};
+
+    fn parse(value: Dictionary) -> UsbmuxEvent {
+        plist::from_value::<ListenMessage>(&Value::Dictionary(value))
+            .unwrap()
+            .into()
+    }
+
+    #[test]
+    fn test_parse_events() {
+        let mut props = Dictionary::new();
+        props.insert("SerialNumber".to_string(), Value::from("00008030-001A"));
+        props.insert("ConnectionType".to_string(), Value::from("USB"));
+        let mut attached = Dictionary::new();
+        attached.insert("MessageType".to_string(), Value::from("Attached"));
+        attached.insert("DeviceID".to_string(), Value::from(3u64));
+        attached.insert("Properties".to_string(), Value::Dictionary(props));
+
+        match parse(attached) {
+            UsbmuxEvent::Attached(info) => {
+                assert_eq!(info.device_id, 3);
+                assert_eq!(info.serial_number, "00008030-001A");
+            }
+            other => panic!("unexpected event: {:?}", other),
+        }
+
+        let mut detached = Dictionary::new();
+        detached.insert("MessageType".to_string(), Value::from("Detached"));
+        detached.insert("DeviceID".to_string(), Value::from(3u64));
+        assert!(matches!(
+            parse(detached),
+            UsbmuxEvent::Detached { device_id: 3 }
+        ));
+    }
+}
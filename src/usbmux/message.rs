@@ -1,69 +1,484 @@
-use plist::Dictionary;
-use util_macro::UsbMuxPlist;
+use std::io::Cursor;
+
+use plist::{Data, Dictionary, Value};
+use serde::{Deserialize, Serialize, Serializer};
 
 pub const USBMUX_VERSION: u32 = 1;
 pub const USBMUX_MSGTYPE: u32 = 8;
 
-#[derive(UsbMuxPlist)]
+pub const USBMUX_CLIENT_VERSION: &str = "usbmuxd-client";
+pub const USBMUX_PROG_NAME: &str = "client";
+pub const USBMUX_LIB_VERSION: u64 = 3;
+
+pub const LOCKDOWN_LABEL: &str = "client";
+
+/// Default upper bound for a usbmuxd reply, header included.
+pub const DEFAULT_MAX_USBMUX_FRAME_SIZE: usize = 1 << 20;
+/// Default upper bound for a length-prefixed plist from lockdownd or a service.
+pub const DEFAULT_MAX_PLIST_FRAME_SIZE: usize = 16 << 20;
+
+/// Size of the usbmuxd header: length, version, message type and tag.
+pub const USBMUX_HEADER_SIZE: usize = 16;
+
+// `Number` values of a usbmuxd `Result` reply.
+pub const RESULT_OK: u64 = 0;
+pub const RESULT_BADCOMMAND: u64 = 1;
+pub const RESULT_BADDEV: u64 = 2;
+pub const RESULT_CONNREFUSED: u64 = 3;
+pub const RESULT_BADVERSION: u64 = 6;
+
+const BPLIST_MAGIC: &[u8] = b"bplist00";
+
+/// Serialization used for outgoing plist messages on a connection.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum PlistFormat {
+    #[default]
+    Xml,
+    Binary,
+}
+
+impl PlistFormat {
+    pub fn encode<T: Serialize>(&self, value: &T) -> Result<Vec<u8>, plist::Error> {
+        let mut bytes = Vec::new();
+        match self {
+            PlistFormat::Xml => plist::to_writer_xml(&mut bytes, value)?,
+            PlistFormat::Binary => plist::to_writer_binary(&mut bytes, value)?,
+        }
+        Ok(bytes)
+    }
+}
+
+/// Decodes a plist payload, accepting both XML and binary (`bplist00`) plists.
+pub fn decode_plist(bytes: &[u8]) -> Result<Value, plist::Error> {
+    if bytes.starts_with(BPLIST_MAGIC) {
+        Value::from_reader(Cursor::new(bytes))
+    } else {
+        Value::from_reader_xml(bytes)
+    }
+}
+
 pub struct UsbmuxMessageHeader {
     pub version: u32,
     pub message: u32,
     pub tag: u32,
 }
-#[derive(UsbMuxPlist)]
-pub struct UsbmuxMessageData {
-    pub MessageType: String,
-    pub PairRecordID: Option<String>,
-    pub ClientVersionString: String,
-    pub ProgName: String,
-    pub kLibUSBMuxVersion: i64,
-    pub DeviceID: Option<u16>,
-    pub PortNumber: Option<u16>,
+
+/// A request to usbmuxd, tagged by its `MessageType`.
+#[derive(Debug, Serialize)]
+#[serde(tag = "MessageType")]
+pub enum UsbmuxRequest {
+    ListDevices,
+    Listen,
+    #[serde(rename = "ReadBUID")]
+    ReadBuid,
+    ReadPairRecord {
+        #[serde(rename = "PairRecordID")]
+        pair_record_id: String,
+    },
+    SavePairRecord {
+        #[serde(rename = "PairRecordID")]
+        pair_record_id: String,
+        #[serde(rename = "PairRecordData")]
+        pair_record_data: Data,
+        #[serde(rename = "DeviceID")]
+        device_id: u16,
+    },
+    DeletePairRecord {
+        #[serde(rename = "PairRecordID")]
+        pair_record_id: String,
+    },
+    Connect {
+        #[serde(rename = "DeviceID")]
+        device_id: u16,
+        /// The device port, in network byte order.
+        #[serde(rename = "PortNumber")]
+        port_number: u16,
+    },
+}
+
+/// The plist body of a usbmuxd request: the request itself plus the client
+/// identification every message carries.
+#[derive(Debug, Serialize)]
+#[serde(rename_all = "PascalCase")]
+pub struct UsbmuxPacket<'a> {
+    #[serde(flatten)]
+    pub request: &'a UsbmuxRequest,
+    pub client_version_string: &'static str,
+    pub prog_name: &'static str,
+    #[serde(rename = "kLibUSBMuxVersion")]
+    pub lib_usbmux_version: u64,
+}
+
+impl<'a> UsbmuxPacket<'a> {
+    pub fn new(request: &'a UsbmuxRequest) -> Self {
+        UsbmuxPacket {
+            request,
+            client_version_string: USBMUX_CLIENT_VERSION,
+            prog_name: USBMUX_PROG_NAME,
+            lib_usbmux_version: USBMUX_LIB_VERSION,
+        }
+    }
+}
+
+/// usbmuxd's `Result` reply.
+#[derive(Debug, Deserialize)]
+#[serde(rename_all = "PascalCase")]
+pub struct UsbmuxResult {
+    pub number: u64,
+}
+
+/// usbmuxd's reply to `ListDevices`.
+#[derive(Debug, Deserialize)]
+#[serde(rename_all = "PascalCase")]
+pub struct DeviceList {
+    pub device_list: Vec<DeviceEntry>,
+}
+
+/// One device as reported by `ListDevices` and `Attached` notifications.
+#[derive(Debug, Deserialize)]
+#[serde(rename_all = "PascalCase")]
+pub struct DeviceEntry {
+    #[serde(rename = "DeviceID")]
+    pub device_id: u16,
+    pub properties: DeviceProperties,
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(rename_all = "PascalCase")]
+pub struct DeviceProperties {
+    pub serial_number: String,
+    pub connection_type: Option<String>,
+    #[serde(rename = "ProductID")]
+    pub product_id: Option<u16>,
+    #[serde(rename = "LocationID")]
+    pub location_id: Option<u32>,
+    pub connection_speed: Option<u32>,
+}
+
+/// usbmuxd's reply to `ReadBUID`.
+#[derive(Debug, Deserialize)]
+pub struct Buid {
+    #[serde(rename = "BUID")]
+    pub buid: String,
+}
+
+/// usbmuxd's reply to `ReadPairRecord`.
+#[derive(Debug, Deserialize)]
+#[serde(rename_all = "PascalCase")]
+pub struct PairRecordData {
+    pub pair_record_data: Data,
+}
+
+/// A notification received while in `Listen` mode.
+#[derive(Debug, Deserialize)]
+#[serde(tag = "MessageType")]
+pub enum ListenMessage {
+    Attached(DeviceEntry),
+    Detached {
+        #[serde(rename = "DeviceID")]
+        device_id: u16,
+    },
+    Paired {
+        #[serde(rename = "DeviceID")]
+        device_id: u16,
+    },
+}
+
+/// A request to lockdownd, tagged by its `Request` name.
+#[derive(Debug, Serialize)]
+#[serde(tag = "Request", rename_all_fields = "PascalCase")]
+pub enum LockdownRequest {
+    QueryType,
+    GetValue {
+        #[serde(
+            skip_serializing_if = "Option::is_none",
+            serialize_with = "unwrap_some"
+        )]
+        domain: Option<String>,
+        #[serde(
+            skip_serializing_if = "Option::is_none",
+            serialize_with = "unwrap_some"
+        )]
+        key: Option<String>,
+    },
+    SetValue {
+        #[serde(
+            skip_serializing_if = "Option::is_none",
+            serialize_with = "unwrap_some"
+        )]
+        domain: Option<String>,
+        #[serde(
+            skip_serializing_if = "Option::is_none",
+            serialize_with = "unwrap_some"
+        )]
+        key: Option<String>,
+        value: Value,
+    },
+    RemoveValue {
+        #[serde(
+            skip_serializing_if = "Option::is_none",
+            serialize_with = "unwrap_some"
+        )]
+        domain: Option<String>,
+        #[serde(
+            skip_serializing_if = "Option::is_none",
+            serialize_with = "unwrap_some"
+        )]
+        key: Option<String>,
+    },
+    StartSession {
+        #[serde(rename = "HostID")]
+        host_id: String,
+        #[serde(rename = "SystemBUID")]
+        system_buid: String,
+    },
+    StopSession {
+        #[serde(rename = "SessionID")]
+        session_id: String,
+    },
+    StartService {
+        service: String,
+        #[serde(
+            skip_serializing_if = "Option::is_none",
+            serialize_with = "unwrap_some"
+        )]
+        escrow_bag: Option<Data>,
+    },
+    Pair {
+        pair_record: Dictionary,
+        protocol_version: String,
+        #[serde(
+            skip_serializing_if = "Option::is_none",
+            serialize_with = "unwrap_some"
+        )]
+        pairing_options: Option<Dictionary>,
+    },
+    ValidatePair {
+        pair_record: Dictionary,
+        protocol_version: String,
+    },
+    Unpair {
+        pair_record: Dictionary,
+        protocol_version: String,
+    },
+}
+
+// plist encodes `Some(x)` as `{"Some": x}` unless it is a direct struct
+// field, which optional fields of a flattened request are not.
+fn unwrap_some<T: Serialize, S: Serializer>(value: &Option<T>, s: S) -> Result<S::Ok, S::Error> {
+    match value {
+        Some(value) => value.serialize(s),
+        None => s.serialize_none(),
+    }
+}
+
+/// The plist body of a lockdownd request.
+#[derive(Debug, Serialize)]
+#[serde(rename_all = "PascalCase")]
+pub struct LockdownPacket<'a> {
+    pub label: &'static str,
+    #[serde(flatten)]
+    pub request: &'a LockdownRequest,
+}
+
+impl<'a> LockdownPacket<'a> {
+    pub fn new(request: &'a LockdownRequest) -> Self {
+        LockdownPacket {
+            label: LOCKDOWN_LABEL,
+            request,
+        }
+    }
+}
+
+/// Fields shared by every lockdownd reply.
+#[derive(Debug, Deserialize)]
+#[serde(rename_all = "PascalCase")]
+pub struct LockdownResponse {
+    pub request: Option<String>,
+    pub error: Option<String>,
+}
+
+/// lockdownd's reply to `QueryType`.
+#[derive(Debug, Deserialize)]
+#[serde(rename_all = "PascalCase")]
+pub struct QueryTypeResponse {
+    #[serde(rename = "Type")]
+    pub service_type: Option<String>,
+    pub error: Option<String>,
+}
+
+/// lockdownd's reply to `StartSession`.
+#[derive(Debug, Deserialize)]
+#[serde(rename_all = "PascalCase")]
+pub struct StartSessionResponse {
+    pub request: Option<String>,
+    #[serde(rename = "SessionID")]
+    pub session_id: Option<String>,
+    #[serde(rename = "EnableSessionSSL", default)]
+    pub enable_session_ssl: bool,
+    pub error: Option<String>,
+}
+
+/// lockdownd's reply to `GetValue`.
+#[derive(Debug, Deserialize)]
+#[serde(rename_all = "PascalCase")]
+pub struct GetValueResponse<T> {
+    pub value: Option<T>,
+    pub error: Option<String>,
+}
+
+/// lockdownd's reply to `Pair`.
+#[derive(Debug, Deserialize)]
+#[serde(rename_all = "PascalCase")]
+pub struct PairResponse {
+    pub escrow_bag: Option<Data>,
+    pub error: Option<String>,
+}
+
+/// lockdownd's reply to `StartService`.
+#[derive(Debug, Deserialize)]
+#[serde(rename_all = "PascalCase")]
+pub struct StartServiceResponse {
+    pub port: Option<u16>,
+    #[serde(rename = "EnableServiceSSL")]
+    pub enable_service_ssl: Option<bool>,
+    pub error: Option<String>,
 }
-#[derive(UsbMuxPlist)]
-pub struct UsbmuxMessage {
-    pub header: UsbmuxMessageHeader,
-    pub data: UsbmuxMessageData,
+
+/// A request to the `com.apple.amfi.lockdown` service.
+#[derive(Debug, Serialize)]
+pub struct AmfiRequest {
+    pub action: u32,
+}
+
+/// A keepalive sent by `com.apple.mobile.heartbeat`, `Marco` every
+/// `Interval` seconds.
+#[derive(Debug, Deserialize)]
+#[serde(rename_all = "PascalCase")]
+pub struct HeartbeatMessage {
+    pub command: String,
+    pub interval: Option<u64>,
 }
 
-pub trait UsbMuxPlist {
-    fn to_plist(&self) -> Dictionary;
+/// The host's answer to a heartbeat, `Polo`.
+#[derive(Debug, Serialize)]
+#[serde(rename_all = "PascalCase")]
+pub struct HeartbeatReply {
+    pub command: &'static str,
 }
 
-#[derive(UsbMuxPlist)]
-pub struct LockdownMessage {
-    pub Label: Option<String>,
-    pub Request: Option<String>,
-    pub HostID: Option<String>,
-    pub SystemBUID: Option<String>,
-    pub Service: Option<String>,
-    pub action: Option<u32>,
-    pub Domain: Option<String>,
-    pub Key: Option<String>,
+/// Reply of the `com.apple.amfi.lockdown` service.
+#[derive(Debug, Deserialize)]
+pub struct AmfiResponse {
+    pub success: Option<bool>,
+    #[serde(rename = "Error")]
+    pub error: Option<String>,
 }
 
 #[cfg(test)]
 mod test {
-    use super::UsbmuxMessageHeader;
-    use crate::usbmux::message::{
-        UsbMuxPlist, UsbmuxMessage, UsbmuxMessageData, USBMUX_MSGTYPE, USBMUX_VERSION,
+    use plist::{Dictionary, Value};
+
+    use super::{
+        decode_plist, DeviceList, LockdownPacket, LockdownRequest, PlistFormat, UsbmuxPacket,
+        UsbmuxRequest,
     };
+
     #[test]
-    fn test_macro() {
-        // let msg = UsbmuxMessage {
-        //     header: UsbmuxMessageHeader {
-        //         version: USBMUX_VERSION,
-        //         message: USBMUX_MSGTYPE,
-        //         tag: 1,
-        //     },
-        //     data: UsbmuxMessageData {
-        //         MessageType: "ListDevices".to_string(),
-        //         PairRecordID: None,
-        //         ClientVersionString: "my-usbmuxd-client".to_string(),
-        //         ProgName: "pymobiledevice-like".to_string(),
-        //         kLibUSBMuxVersion: 3,
-        //     },
-        // };
-        // println!("{:#?}", msg.to_plist())
+    fn test_usbmux_request() {
+        let request = UsbmuxRequest::Connect {
+            device_id: 3,
+            port_number: 62078u16.to_be(),
+        };
+        let value = plist::to_value(&UsbmuxPacket::new(&request)).unwrap();
+        let dict = value.as_dictionary().unwrap();
+
+        assert_eq!(
+            dict.get("MessageType").and_then(|v| v.as_string()),
+            Some("Connect")
+        );
+        assert_eq!(
+            dict.get("DeviceID").and_then(|v| v.as_unsigned_integer()),
+            Some(3)
+        );
+        assert_eq!(
+            dict.get("PortNumber").and_then(|v| v.as_unsigned_integer()),
+            Some(62078u16.to_be() as u64)
+        );
+        assert_eq!(
+            dict.get("kLibUSBMuxVersion")
+                .and_then(|v| v.as_unsigned_integer()),
+            Some(3)
+        );
+    }
+
+    #[test]
+    fn test_lockdown_request() {
+        let request = LockdownRequest::GetValue {
+            domain: Some("com.apple.security.mac.amfi".to_string()),
+            key: Some("DeveloperModeStatus".to_string()),
+        };
+        let value = plist::to_value(&LockdownPacket::new(&request)).unwrap();
+        let dict = value.as_dictionary().unwrap();
+
+        assert_eq!(
+            dict.get("Label").and_then(|v| v.as_string()),
+            Some("client")
+        );
+        assert_eq!(
+            dict.get("Request").and_then(|v| v.as_string()),
+            Some("GetValue")
+        );
+        assert_eq!(
+            dict.get("Domain").and_then(|v| v.as_string()),
+            Some("com.apple.security.mac.amfi")
+        );
+        assert_eq!(
+            dict.get("Key").and_then(|v| v.as_string()),
+            Some("DeveloperModeStatus")
+        );
+
+        let value = plist::to_value(&LockdownPacket::new(&LockdownRequest::QueryType)).unwrap();
+        assert_eq!(value.as_dictionary().unwrap().len(), 2);
+    }
+
+    #[test]
+    fn test_device_list_response() {
+        let mut props = Dictionary::new();
+        props.insert("SerialNumber".to_string(), Value::from("00008030-001A"));
+        props.insert("ConnectionType".to_string(), Value::from("Network"));
+        let mut device = Dictionary::new();
+        device.insert("DeviceID".to_string(), Value::from(5u64));
+        device.insert("Properties".to_string(), Value::Dictionary(props));
+        let mut response = Dictionary::new();
+        response.insert(
+            "DeviceList".to_string(),
+            Value::Array(vec![Value::Dictionary(device)]),
+        );
+
+        let list: DeviceList = plist::from_value(&Value::Dictionary(response)).unwrap();
+        assert_eq!(list.device_list[0].device_id, 5);
+        assert_eq!(
+            list.device_list[0].properties.connection_type.as_deref(),
+            Some("Network")
+        );
+        assert_eq!(list.device_list[0].properties.product_id, None);
+    }
+
+    #[test]
+    fn test_plist_formats() {
+        let mut dict = Dictionary::new();
+        dict.insert("MessageType".to_string(), Value::from("Result"));
+        dict.insert("Number".to_string(), Value::from(0u64));
+        let value = Value::Dictionary(dict);
+
+        for format in [PlistFormat::Xml, PlistFormat::Binary] {
+            let bytes = format.encode(&value).unwrap();
+            assert_eq!(
+                bytes.starts_with(b"bplist00"),
+                format == PlistFormat::Binary
+            );
+            assert_eq!(decode_plist(&bytes).unwrap(), value);
+        }
     }
 }
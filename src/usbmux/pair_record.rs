@@ -0,0 +1,428 @@
+use std::{
+    fmt,
+    path::{Path, PathBuf},
+};
+
+use plist::Data;
+use serde::{Deserialize, Deserializer, Serialize, Serializer};
+use tokio::{fs::OpenOptions, io::AsyncWriteExt};
+use zeroize::Zeroizing;
+
+use super::encryption::{is_sealed, open, seal};
+use super::errors::PairRecordError;
+use super::message::decode_plist;
+
+pub use super::encryption::Passphrase;
+
+/// Pair record kept by usbmuxd next to the device records, holding the host's
+/// `SystemBUID`. Not a device record.
+const SYSTEM_CONFIGURATION: &str = "SystemConfiguration";
+/// Unix permissions of pair record files, which hold the host private key.
+#[cfg(unix)]
+const RECORD_FILE_MODE: u32 = 0o600;
+/// Unix permissions of pair record directories created by the store.
+#[cfg(unix)]
+const RECORD_DIR_MODE: u32 = 0o700;
+
+/// A device pair record, as stored by usbmuxd and libimobiledevice. The
+/// private keys are wiped from memory when the record is dropped.
+#[derive(Debug, Serialize, Deserialize)]
+#[serde(rename_all = "PascalCase")]
+pub struct PairRecord {
+    #[serde(rename = "HostID")]
+    pub host_id: String,
+    #[serde(rename = "SystemBUID")]
+    pub system_buid: String,
+    pub host_certificate: Data,
+    pub host_private_key: PrivateKey,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub device_certificate: Option<Data>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub root_certificate: Option<Data>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub root_private_key: Option<PrivateKey>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub escrow_bag: Option<Data>,
+    #[serde(rename = "WiFiMACAddress", skip_serializing_if = "Option::is_none")]
+    pub wifi_mac_address: Option<String>,
+}
+
+/// A PEM private key from a pair record, serialized as plist data. Wiped from
+/// memory when dropped and kept out of `Debug` output.
+#[derive(Clone, PartialEq, Eq)]
+pub struct PrivateKey(Zeroizing<Vec<u8>>);
+
+impl From<Vec<u8>> for PrivateKey {
+    fn from(key: Vec<u8>) -> Self {
+        PrivateKey(Zeroizing::new(key))
+    }
+}
+
+impl AsRef<[u8]> for PrivateKey {
+    fn as_ref(&self) -> &[u8] {
+        &self.0
+    }
+}
+
+impl fmt::Debug for PrivateKey {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str("PrivateKey(..)")
+    }
+}
+
+impl Serialize for PrivateKey {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        serializer.serialize_bytes(&self.0)
+    }
+}
+
+impl<'de> Deserialize<'de> for PrivateKey {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        Ok(PrivateKey::from(Vec::from(Data::deserialize(
+            deserializer,
+        )?)))
+    }
+}
+
+impl PairRecord {
+    /// Parses a pair record from XML or binary plist bytes.
+    pub fn from_bytes(bytes: &[u8]) -> Result<Self, PairRecordError> {
+        Ok(plist::from_value(&decode_plist(bytes)?)?)
+    }
+
+    /// Serializes the pair record as an XML plist.
+    pub fn to_bytes(&self) -> Result<Vec<u8>, PairRecordError> {
+        let mut bytes = Vec::new();
+        plist::to_writer_xml(&mut bytes, self)?;
+        Ok(bytes)
+    }
+
+    /// Parses a pair record file, decrypting it with `passphrase` if it is
+    /// encrypted. `name` identifies the record in errors.
+    async fn decode(
+        bytes: Vec<u8>,
+        passphrase: Option<&Passphrase>,
+        name: &str,
+    ) -> Result<Self, PairRecordError> {
+        if !is_sealed(&bytes) {
+            return PairRecord::from_bytes(&bytes);
+        }
+        let passphrase = passphrase
+            .ok_or_else(|| PairRecordError::Encrypted(name.to_string()))?
+            .clone();
+        let plaintext = run_blocking(move || open(&passphrase, &bytes)).await?;
+        PairRecord::from_bytes(&plaintext)
+    }
+
+    /// Serializes the pair record, encrypted with `passphrase` if given.
+    async fn encode(
+        &self,
+        passphrase: Option<&Passphrase>,
+    ) -> Result<Zeroizing<Vec<u8>>, PairRecordError> {
+        let bytes = Zeroizing::new(self.to_bytes()?);
+        match passphrase {
+            Some(passphrase) => {
+                let passphrase = passphrase.clone();
+                let sealed = run_blocking(move || seal(&passphrase, &bytes)).await?;
+                Ok(Zeroizing::new(sealed))
+            }
+            None => Ok(bytes),
+        }
+    }
+
+    /// Reads a pair record exported with [`PairRecord::write_file`].
+    pub async fn read_file(
+        path: impl AsRef<Path>,
+        passphrase: Option<&Passphrase>,
+    ) -> Result<Self, PairRecordError> {
+        let path = path.as_ref();
+        let bytes = tokio::fs::read(path).await?;
+        PairRecord::decode(bytes, passphrase, &path.display().to_string()).await
+    }
+
+    /// Writes the pair record to a new file at `path`, encrypted with
+    /// `passphrase` if given. The file holds the host private key, so on Unix
+    /// it is readable by the owner only.
+    ///
+    /// # Errors
+    ///
+    /// Returns [`PairRecordError::FileExists`] rather than overwriting an
+    /// existing file.
+    pub async fn write_file(
+        &self,
+        path: impl AsRef<Path>,
+        passphrase: Option<&Passphrase>,
+    ) -> Result<(), PairRecordError> {
+        let path = path.as_ref();
+        let mut options = OpenOptions::new();
+        options.write(true).create_new(true);
+        let bytes = self.encode(passphrase).await?;
+        match write_private_file(path, &bytes, &options).await {
+            Ok(()) => Ok(()),
+            Err(e) if e.kind() == std::io::ErrorKind::AlreadyExists => {
+                Err(PairRecordError::FileExists(path.display().to_string()))
+            }
+            Err(e) => Err(e.into()),
+        }
+    }
+}
+
+/// A directory of `<UDID>.plist` pair records, laid out like the lockdown
+/// directory shared by usbmuxd and libimobiledevice.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct PairRecordStore {
+    pub path: PathBuf,
+    /// Encrypts records written to the store and decrypts encrypted ones
+    /// read from it. Plain records are still read without it.
+    pub passphrase: Option<Passphrase>,
+}
+
+impl PairRecordStore {
+    /// Creates a store rooted at `path`.
+    pub fn new(path: impl Into<PathBuf>) -> Self {
+        PairRecordStore {
+            path: path.into(),
+            passphrase: None,
+        }
+    }
+
+    /// Creates a store rooted at `path` that keeps records encrypted with
+    /// `passphrase`.
+    pub fn encrypted(path: impl Into<PathBuf>, passphrase: Passphrase) -> Self {
+        PairRecordStore {
+            path: path.into(),
+            passphrase: Some(passphrase),
+        }
+    }
+
+    /// Returns the store in the platform's standard lockdown directory.
+    pub fn system() -> Self {
+        PairRecordStore::new(PairRecordStore::system_path())
+    }
+
+    /// `%ProgramData%\Apple\Lockdown` on Windows, `/var/db/lockdown` on
+    /// macOS and `/var/lib/lockdown` elsewhere.
+    pub fn system_path() -> PathBuf {
+        if cfg!(windows) {
+            let program_data =
+                std::env::var("ProgramData").unwrap_or_else(|_| "C:\\ProgramData".to_string());
+            Path::new(&program_data).join("Apple").join("Lockdown")
+        } else if cfg!(target_os = "macos") {
+            PathBuf::from("/var/db/lockdown")
+        } else {
+            PathBuf::from("/var/lib/lockdown")
+        }
+    }
+
+    /// Path of the pair record for `udid`.
+    pub fn record_path(&self, udid: &str) -> PathBuf {
+        self.path.join(format!("{}.plist", udid))
+    }
+
+    /// Reads the pair record for `udid`.
+    ///
+    /// # Errors
+    ///
+    /// Returns [`PairRecordError::NotFound`] if the store has no record for
+    /// the device, and [`PairRecordError::Encrypted`] if the record is
+    /// encrypted and the store has no passphrase.
+    pub async fn read(&self, udid: &str) -> Result<PairRecord, PairRecordError> {
+        let bytes = match tokio::fs::read(self.record_path(udid)).await {
+            Ok(bytes) => bytes,
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => {
+                return Err(PairRecordError::NotFound(udid.to_string()))
+            }
+            Err(e) => return Err(e.into()),
+        };
+        PairRecord::decode(bytes, self.passphrase.as_ref(), udid).await
+    }
+
+    /// Writes the pair record for `udid`, creating the directory if needed.
+    /// The record is encrypted when the store has a passphrase.
+    pub async fn save(&self, udid: &str, record: &PairRecord) -> Result<(), PairRecordError> {
+        let bytes = record.encode(self.passphrase.as_ref()).await?;
+        let mut builder = tokio::fs::DirBuilder::new();
+        builder.recursive(true);
+        #[cfg(unix)]
+        builder.mode(RECORD_DIR_MODE);
+        builder.create(&self.path).await?;
+
+        let mut options = OpenOptions::new();
+        options.write(true).create(true).truncate(true);
+        write_private_file(&self.record_path(udid), &bytes, &options).await?;
+        Ok(())
+    }
+
+    /// Removes the pair record for `udid`.
+    pub async fn delete(&self, udid: &str) -> Result<(), PairRecordError> {
+        match tokio::fs::remove_file(self.record_path(udid)).await {
+            Ok(()) => Ok(()),
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => {
+                Err(PairRecordError::NotFound(udid.to_string()))
+            }
+            Err(e) => Err(e.into()),
+        }
+    }
+
+    /// Lists the UDIDs that have a pair record in the store.
+    pub async fn list(&self) -> Result<Vec<String>, PairRecordError> {
+        let mut udids = Vec::new();
+        let mut entries = tokio::fs::read_dir(&self.path).await?;
+        while let Some(entry) = entries.next_entry().await? {
+            let path = entry.path();
+            if path.extension().and_then(|ext| ext.to_str()) != Some("plist") {
+                continue;
+            }
+            if let Some(udid) = path.file_stem().and_then(|stem| stem.to_str()) {
+                if udid != SYSTEM_CONFIGURATION {
+                    udids.push(udid.to_string());
+                }
+            }
+        }
+        udids.sort();
+        Ok(udids)
+    }
+}
+
+/// Runs `f`, which encrypts or decrypts with a passphrase, on a blocking
+/// thread: deriving the key takes hundreds of milliseconds.
+async fn run_blocking<T, F>(f: F) -> Result<T, PairRecordError>
+where
+    T: Send + 'static,
+    F: FnOnce() -> Result<T, PairRecordError> + Send + 'static,
+{
+    tokio::task::spawn_blocking(f)
+        .await
+        .map_err(std::io::Error::from)?
+}
+
+/// Writes `bytes` to the file at `path` opened with `options`, readable and
+/// writable by the owner only on Unix, even if the file existed before.
+async fn write_private_file(
+    path: &Path,
+    bytes: &[u8],
+    options: &OpenOptions,
+) -> std::io::Result<()> {
+    let mut options = options.clone();
+    #[cfg(unix)]
+    options.mode(RECORD_FILE_MODE);
+    let mut file = options.open(path).await?;
+    #[cfg(unix)]
+    {
+        use std::os::unix::fs::PermissionsExt;
+        file.set_permissions(std::fs::Permissions::from_mode(RECORD_FILE_MODE))
+            .await?;
+    }
+    file.write_all(bytes).await?;
+    file.flush().await
+}
+
+#[cfg(test)]
+mod test {
+    use plist::Data;
+
+    use super::{PairRecord, PairRecordStore, Passphrase};
+    use crate::usbmux::errors::PairRecordError;
+
+    fn sample_record() -> PairRecord {
+        PairRecord {
+            host_id: "2A6E5D36-5B2B-4E3C-9D8B-1F2E3D4C5B6A".to_string(),
+            system_buid: "30F0A1B2-C3D4-E5F6-0718-293A4B5C6D7E".to_string(),
+            host_certificate: Data::new(b"host cert".to_vec()),
+            host_private_key: b"host key".to_vec().into(),
+            device_certificate: Some(Data::new(b"device cert".to_vec())),
+            root_certificate: None,
+            root_private_key: None,
+            escrow_bag: Some(Data::new(vec![1, 2, 3])),
+            wifi_mac_address: None,
+        }
+    }
+
+    #[tokio::test]
+    async fn test_store_roundtrip() {
+        let dir = std::env::temp_dir().join(format!("pair-records-{}", std::process::id()));
+        let store = PairRecordStore::new(&dir);
+        let udid = "00008030-001A";
+
+        assert!(matches!(
+            store.read(udid).await,
+            Err(PairRecordError::NotFound(_))
+        ));
+
+        store.save(udid, &sample_record()).await.unwrap();
+        #[cfg(unix)]
+        {
+            use std::os::unix::fs::PermissionsExt;
+            let mode = |path| std::fs::metadata(path).unwrap().permissions().mode() & 0o777;
+            assert_eq!(mode(store.record_path(udid)), 0o600);
+            assert_eq!(mode(dir.clone()), 0o700);
+        }
+        tokio::fs::write(dir.join("SystemConfiguration.plist"), b"")
+            .await
+            .unwrap();
+        assert_eq!(store.list().await.unwrap(), vec![udid.to_string()]);
+
+        let record = store.read(udid).await.unwrap();
+        assert_eq!(record.host_id, sample_record().host_id);
+        assert_eq!(record.escrow_bag, sample_record().escrow_bag);
+        assert_eq!(record.root_certificate, None);
+
+        store.delete(udid).await.unwrap();
+        assert!(store.list().await.unwrap().is_empty());
+        tokio::fs::remove_dir_all(&dir).await.unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_encrypted_store() {
+        let dir = std::env::temp_dir().join(format!("pair-records-enc-{}", std::process::id()));
+        let store = PairRecordStore::encrypted(&dir, Passphrase::new("correct horse"));
+        let udid = "00008030-001A";
+
+        store.save(udid, &sample_record()).await.unwrap();
+        let bytes = tokio::fs::read(store.record_path(udid)).await.unwrap();
+        assert!(!bytes.windows(8).any(|w| w == b"host key"));
+        assert_eq!(
+            store.read(udid).await.unwrap().host_private_key,
+            sample_record().host_private_key
+        );
+
+        assert!(matches!(
+            PairRecordStore::new(&dir).read(udid).await,
+            Err(PairRecordError::Encrypted(_))
+        ));
+        assert!(matches!(
+            PairRecordStore::encrypted(&dir, Passphrase::new("wrong"))
+                .read(udid)
+                .await,
+            Err(PairRecordError::WrongPassphrase)
+        ));
+        tokio::fs::remove_dir_all(&dir).await.unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_write_file() {
+        let path = std::env::temp_dir().join(format!("pair-record-{}.plist", std::process::id()));
+        let passphrase = Passphrase::new("correct horse");
+
+        sample_record()
+            .write_file(&path, Some(&passphrase))
+            .await
+            .unwrap();
+        #[cfg(unix)]
+        {
+            use std::os::unix::fs::PermissionsExt;
+            let mode = std::fs::metadata(&path).unwrap().permissions().mode();
+            assert_eq!(mode & 0o777, 0o600);
+        }
+        assert!(matches!(
+            sample_record().write_file(&path, None).await,
+            Err(PairRecordError::FileExists(_))
+        ));
+
+        let record = PairRecord::read_file(&path, Some(&passphrase))
+            .await
+            .unwrap();
+        assert_eq!(record.host_id, sample_record().host_id);
+        tokio::fs::remove_file(&path).await.unwrap();
+    }
+}
@@ -1,27 +1,42 @@
 use rustls::client::danger::{HandshakeSignatureValid, ServerCertVerified, ServerCertVerifier};
+use rustls::crypto::{aws_lc_rs, verify_tls12_signature, verify_tls13_signature, CryptoProvider};
 use rustls::pki_types::{CertificateDer, PrivateKeyDer, ServerName, UnixTime};
 use rustls::ClientConfig;
-use rustls::DigitallySignedStruct;
+use rustls::{CertificateError, DigitallySignedStruct};
 use rustls_pki_types::pem::PemObject;
 use std::error::Error;
 use std::sync::Arc;
-use tokio::net::TcpStream;
+use tokio::io::{AsyncRead, AsyncWrite};
 use tokio_rustls::{client::TlsStream, TlsConnector};
 
 use super::errors::SslError;
 
-pub async fn ssl_wrap_socket(
-    socket: TcpStream,
+/// Wraps `socket` in TLS, authenticating with the host certificate and key.
+/// The device must present `device_cert_pem`, the `DeviceCertificate` of the
+/// pair record; `None` accepts any certificate.
+pub async fn ssl_wrap_socket<S: AsyncRead + AsyncWrite + Unpin>(
+    socket: S,
     cert_pem: &[u8],
     key_pem: &[u8],
-) -> Result<TlsStream<TcpStream>, SslError> {
+    device_cert_pem: Option<&[u8]>,
+) -> Result<TlsStream<S>, SslError> {
     let mut cert = Vec::new();
     cert.push(CertificateDer::from_pem_slice(cert_pem)?);
     let key = PrivateKeyDer::from_pem_slice(key_pem)?;
 
-    let mut config = ClientConfig::builder()
+    let provider = Arc::new(aws_lc_rs::default_provider());
+    let device_certificate = device_cert_pem
+        .map(CertificateDer::from_pem_slice)
+        .transpose()?;
+    let verifier = DeviceCertVerifier {
+        device_certificate,
+        provider: provider.clone(),
+    };
+
+    let mut config = ClientConfig::builder_with_provider(provider)
+        .with_safe_default_protocol_versions()?
         .dangerous()
-        .with_custom_certificate_verifier(Arc::new(DangerousVerifier))
+        .with_custom_certificate_verifier(Arc::new(verifier))
         .with_client_auth_cert(cert, key)?;
 
     let domain = ServerName::try_from("localhost")?;
@@ -31,40 +46,155 @@ pub async fn ssl_wrap_socket(
     Ok(tls_stream)
 }
 
+/// Runs a TLS handshake on `socket` and hands the socket back for plaintext
+/// use, for services that only authenticate the host with TLS.
+pub async fn ssl_handshake_only<S: AsyncRead + AsyncWrite + Unpin>(
+    socket: S,
+    cert_pem: &[u8],
+    key_pem: &[u8],
+    device_cert_pem: Option<&[u8]>,
+) -> Result<S, SslError> {
+    let (socket, _) = ssl_wrap_socket(socket, cert_pem, key_pem, device_cert_pem)
+        .await?
+        .into_inner();
+    Ok(socket)
+}
+
+/// Accepts the device certificate from the pair record and nothing else.
+/// The device presents the certificate the host issued for it when pairing,
+/// so there is no chain or name to check; handshake signatures are verified
+/// against the presented certificate as usual.
 #[derive(Debug)]
-struct DangerousVerifier;
+struct DeviceCertVerifier {
+    /// The pinned certificate. `None` accepts any certificate.
+    device_certificate: Option<CertificateDer<'static>>,
+    provider: Arc<CryptoProvider>,
+}
 
-impl ServerCertVerifier for DangerousVerifier {
+impl ServerCertVerifier for DeviceCertVerifier {
     fn verify_server_cert(
         &self,
-        _end_entity: &CertificateDer<'_>,
+        end_entity: &CertificateDer<'_>,
         _intermediates: &[CertificateDer<'_>],
         _server_name: &ServerName<'_>,
         _ocsp_response: &[u8],
         _now: UnixTime,
     ) -> Result<ServerCertVerified, rustls::Error> {
-        Ok(ServerCertVerified::assertion()) // Bypass verification
+        match &self.device_certificate {
+            Some(pinned) if pinned.as_ref() != end_entity.as_ref() => Err(
+                rustls::Error::InvalidCertificate(CertificateError::ApplicationVerificationFailure),
+            ),
+            _ => Ok(ServerCertVerified::assertion()),
+        }
     }
 
     fn verify_tls12_signature(
         &self,
-        _message: &[u8],
-        _cert: &CertificateDer<'_>,
-        _dss: &DigitallySignedStruct,
+        message: &[u8],
+        cert: &CertificateDer<'_>,
+        dss: &DigitallySignedStruct,
     ) -> Result<HandshakeSignatureValid, rustls::Error> {
-        Ok(HandshakeSignatureValid::assertion())
+        verify_tls12_signature(
+            message,
+            cert,
+            dss,
+            &self.provider.signature_verification_algorithms,
+        )
     }
 
     fn verify_tls13_signature(
         &self,
-        _message: &[u8],
-        _cert: &CertificateDer<'_>,
-        _dss: &DigitallySignedStruct,
+        message: &[u8],
+        cert: &CertificateDer<'_>,
+        dss: &DigitallySignedStruct,
     ) -> Result<HandshakeSignatureValid, rustls::Error> {
-        Ok(HandshakeSignatureValid::assertion())
+        verify_tls13_signature(
+            message,
+            cert,
+            dss,
+            &self.provider.signature_verification_algorithms,
+        )
     }
 
     fn supported_verify_schemes(&self) -> Vec<rustls::SignatureScheme> {
-        vec![rustls::SignatureScheme::RSA_PKCS1_SHA256] // Dummy value
+        self.provider
+            .signature_verification_algorithms
+            .supported_schemes()
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::sync::Arc;
+
+    use rcgen::{CertificateParams, KeyPair};
+    use rustls::pki_types::{CertificateDer, PrivateKeyDer};
+    use rustls::ServerConfig;
+    use rustls_pki_types::pem::PemObject;
+    use tokio::io::{AsyncReadExt, AsyncWriteExt};
+    use tokio_rustls::TlsAcceptor;
+
+    use super::ssl_wrap_socket;
+
+    /// Returns a self-signed certificate and key, both PEM encoded.
+    fn identity() -> (String, String) {
+        let key = KeyPair::generate().unwrap();
+        let cert = CertificateParams::new(vec![])
+            .unwrap()
+            .self_signed(&key)
+            .unwrap();
+        (cert.pem(), key.serialize_pem())
+    }
+
+    /// Connects to a TLS server presenting `device` while pinning `pinned`.
+    async fn handshake(device: &(String, String), pinned: Option<&str>) -> bool {
+        let (host_cert, host_key) = identity();
+        let (client_end, server_end) = tokio::io::duplex(16384);
+
+        let config = ServerConfig::builder_with_provider(Arc::new(
+            rustls::crypto::aws_lc_rs::default_provider(),
+        ))
+        .with_safe_default_protocol_versions()
+        .unwrap()
+        .with_no_client_auth()
+        .with_single_cert(
+            vec![CertificateDer::from_pem_slice(device.0.as_bytes()).unwrap()],
+            PrivateKeyDer::from_pem_slice(device.1.as_bytes()).unwrap(),
+        )
+        .unwrap();
+        let device_task = tokio::spawn(async move {
+            if let Ok(mut tls) = TlsAcceptor::from(Arc::new(config)).accept(server_end).await {
+                tls.write_all(b"ok").await.unwrap();
+                tls.flush().await.unwrap();
+            }
+        });
+
+        let result = ssl_wrap_socket(
+            client_end,
+            host_cert.as_bytes(),
+            host_key.as_bytes(),
+            pinned.map(str::as_bytes),
+        )
+        .await;
+        let connected = match result {
+            Ok(mut tls) => {
+                let mut reply = [0u8; 2];
+                tls.read_exact(&mut reply).await.unwrap();
+                &reply == b"ok"
+            }
+            Err(_) => false,
+        };
+        device_task.await.unwrap();
+        connected
+    }
+
+    #[tokio::test]
+    async fn test_device_certificate_pinning() {
+        let device = identity();
+        let impostor = identity();
+
+        assert!(handshake(&device, Some(&device.0)).await);
+        assert!(!handshake(&impostor, Some(&device.0)).await);
+        assert!(handshake(&impostor, None).await);
     }
 }
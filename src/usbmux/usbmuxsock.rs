@@ -1,27 +1,151 @@
-use tokio::{io::AsyncWriteExt, net::TcpStream};
+use std::{
+    io,
+    pin::Pin,
+    task::{Context, Poll},
+};
+
+#[cfg(unix)]
+use std::path::PathBuf;
+
+#[cfg(unix)]
+use tokio::net::UnixStream;
+use tokio::{
+    io::{AsyncRead, AsyncWrite, AsyncWriteExt, ReadBuf},
+    net::TcpStream,
+};
 
 use byteorder::ReadBytesExt;
 
 use super::errors::UsbmuxSockError;
 
+/// Environment variable used by libimobiledevice and friends to override the
+/// usbmuxd address, either `UNIX:/path/to/socket` or `host:port`.
+pub const USBMUXD_SOCKET_ADDRESS_ENV: &str = "USBMUXD_SOCKET_ADDRESS";
+
+/// Where the usbmuxd daemon is listening.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum UsbmuxAddress {
+    Tcp(String),
+    #[cfg(unix)]
+    Unix(PathBuf),
+}
+
+impl UsbmuxAddress {
+    /// Parses an address in the `USBMUXD_SOCKET_ADDRESS` format.
+    pub fn parse(address: &str) -> Self {
+        #[cfg(unix)]
+        if let Some(path) = address.strip_prefix("UNIX:") {
+            return UsbmuxAddress::Unix(PathBuf::from(path));
+        }
+        UsbmuxAddress::Tcp(address.to_string())
+    }
+
+    /// Returns the address from `USBMUXD_SOCKET_ADDRESS`, or the platform
+    /// default: `127.0.0.1:27015` on Windows and `/var/run/usbmuxd` elsewhere.
+    pub fn from_env() -> Self {
+        match std::env::var(USBMUXD_SOCKET_ADDRESS_ENV) {
+            Ok(address) if !address.is_empty() => UsbmuxAddress::parse(&address),
+            _ => UsbmuxAddress::default(),
+        }
+    }
+}
+
+impl Default for UsbmuxAddress {
+    #[cfg(unix)]
+    fn default() -> Self {
+        UsbmuxAddress::Unix(PathBuf::from("/var/run/usbmuxd"))
+    }
+
+    #[cfg(not(unix))]
+    fn default() -> Self {
+        UsbmuxAddress::Tcp("127.0.0.1:27015".to_string())
+    }
+}
+
+/// The stream a [`UsbmuxSock`] is connected through.
+pub enum UsbmuxStream {
+    Tcp(TcpStream),
+    #[cfg(unix)]
+    Unix(UnixStream),
+}
+
+impl AsyncRead for UsbmuxStream {
+    fn poll_read(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut ReadBuf<'_>,
+    ) -> Poll<io::Result<()>> {
+        match self.get_mut() {
+            UsbmuxStream::Tcp(stream) => Pin::new(stream).poll_read(cx, buf),
+            #[cfg(unix)]
+            UsbmuxStream::Unix(stream) => Pin::new(stream).poll_read(cx, buf),
+        }
+    }
+}
+
+impl AsyncWrite for UsbmuxStream {
+    fn poll_write(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &[u8],
+    ) -> Poll<io::Result<usize>> {
+        match self.get_mut() {
+            UsbmuxStream::Tcp(stream) => Pin::new(stream).poll_write(cx, buf),
+            #[cfg(unix)]
+            UsbmuxStream::Unix(stream) => Pin::new(stream).poll_write(cx, buf),
+        }
+    }
+
+    fn poll_flush(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        match self.get_mut() {
+            UsbmuxStream::Tcp(stream) => Pin::new(stream).poll_flush(cx),
+            #[cfg(unix)]
+            UsbmuxStream::Unix(stream) => Pin::new(stream).poll_flush(cx),
+        }
+    }
+
+    fn poll_shutdown(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        match self.get_mut() {
+            UsbmuxStream::Tcp(stream) => Pin::new(stream).poll_shutdown(cx),
+            #[cfg(unix)]
+            UsbmuxStream::Unix(stream) => Pin::new(stream).poll_shutdown(cx),
+        }
+    }
+}
+
 /// Represents a connection to the usbmuxd socket.
 ///
-/// This struct manages a TCP connection to the usbmuxd service,
-/// typically running on localhost:27015. It provides methods to
-/// create a new connection and to reset (reconnect) the socket.
+/// This struct manages a connection to the usbmuxd service, either over TCP
+/// (localhost:27015 with Apple Mobile Device Support on Windows) or over a
+/// Unix domain socket (`/var/run/usbmuxd` on Linux and macOS). It provides
+/// methods to create a new connection and to reset (reconnect) the socket.
 pub struct UsbmuxSock {
-    /// The underlying asynchronous TCP stream.
-    pub sock: TcpStream,
+    /// The underlying asynchronous stream.
+    pub sock: UsbmuxStream,
 }
 
 impl UsbmuxSock {
-    /// Creates a new `UsbmuxSock` by connecting to the usbmuxd socket.
+    /// Creates a new `UsbmuxSock` by connecting to the usbmuxd socket given by
+    /// [`UsbmuxAddress::from_env`].
     ///
     /// # Errors
     ///
     /// Returns a [`UsbmuxSockError`] if the connection fails.
     pub async fn new() -> Result<Self, UsbmuxSockError> {
-        let mut stream = TcpStream::connect("127.0.0.1:27015").await?;
+        UsbmuxSock::connect(&UsbmuxAddress::from_env()).await
+    }
+
+    /// Creates a new `UsbmuxSock` connected to `address`.
+    ///
+    /// # Errors
+    ///
+    /// Returns a [`UsbmuxSockError`] if the connection fails.
+    pub async fn connect(address: &UsbmuxAddress) -> Result<Self, UsbmuxSockError> {
+        let stream = match address {
+            UsbmuxAddress::Tcp(addr) => UsbmuxStream::Tcp(TcpStream::connect(addr).await?),
+            #[cfg(unix)]
+            UsbmuxAddress::Unix(path) => UsbmuxStream::Unix(UnixStream::connect(path).await?),
+        };
         Ok(UsbmuxSock { sock: stream })
     }
 
@@ -36,3 +160,21 @@ impl UsbmuxSock {
         Ok(new_sock)
     }
 }
+
+#[cfg(test)]
+mod test {
+    use super::UsbmuxAddress;
+
+    #[test]
+    fn test_parse_address() {
+        assert_eq!(
+            UsbmuxAddress::parse("127.0.0.1:27015"),
+            UsbmuxAddress::Tcp("127.0.0.1:27015".to_string())
+        );
+        #[cfg(unix)]
+        assert_eq!(
+            UsbmuxAddress::parse("UNIX:/var/run/usbmuxd"),
+            UsbmuxAddress::Unix("/var/run/usbmuxd".into())
+        );
+    }
+}
@@ -6,9 +6,16 @@ use tokio::{
     net::TcpStream,
 };
 
+use crate::transport::BoxedTransport;
+
+/// Default upper bound for the payload of a received HTTP/2 frame.
+pub const DEFAULT_MAX_XPC_FRAME_SIZE: usize = 1 << 20;
+
 pub struct XpcHandler {
-    pub sock: TcpStream,
+    pub sock: BoxedTransport,
     pub dtport: Option<u16>,
+    /// Largest frame payload accepted from the device.
+    pub max_frame_size: usize,
 }
 
 impl XpcHandler {
@@ -16,9 +23,15 @@ impl XpcHandler {
         let sock = TcpStream::connect(format!("[{}]:{}", server_addr, server_port))
             .await
             .unwrap();
+        XpcHandler::from_transport(Box::new(sock))
+    }
+
+    /// Creates a handler running over an already connected transport.
+    pub fn from_transport(sock: BoxedTransport) -> Self {
         XpcHandler {
             sock: sock,
             dtport: None,
+            max_frame_size: DEFAULT_MAX_XPC_FRAME_SIZE,
         }
     }
 
@@ -122,9 +135,15 @@ impl XpcHandler {
             self.sock.read_exact(&mut header).await?;
 
             let length_of_payload =
-                (header[2] as u32) | ((header[1] as u32) << 8) | ((header[0] as u32) << 16);
+                (header[2] as usize) | ((header[1] as usize) << 8) | ((header[0] as usize) << 16);
+            if length_of_payload > self.max_frame_size {
+                return Err(ReceiveFrameError::FrameTooLarge {
+                    length: length_of_payload,
+                    max: self.max_frame_size,
+                });
+            }
 
-            let mut payload = vec![0u8; length_of_payload as usize];
+            let mut payload = vec![0u8; length_of_payload];
             self.sock.read_exact(&mut payload).await?;
 
             let hex_arr = hex::encode(&payload);
@@ -152,9 +171,60 @@ impl XpcHandler {
             .windows(4)
             .position(|window| window == b"Port")
             .ok_or(ParseError::MatchError("port in service".to_string()))?;
-        let port = &slice[(port_index + 16)..(port_index + 21)];
+        let port = slice
+            .get((port_index + 16)..(port_index + 21))
+            .ok_or(ParseError::MatchError("port value in service".to_string()))?;
         let port_str = std::str::from_utf8(port)?;
         let port_num: u16 = port_str.parse()?;
         Ok(port_num)
     }
 }
+
+#[cfg(test)]
+mod test {
+    use tokio::io::AsyncWriteExt;
+
+    use super::{errors::ReceiveFrameError, XpcHandler};
+
+    #[tokio::test]
+    async fn test_receive_frames_limits() {
+        let (client_end, mut server) = tokio::io::duplex(4096);
+        let mut handler = XpcHandler::from_transport(Box::new(client_end));
+        handler.max_frame_size = 16384;
+
+        // A 16 MiB DATA frame header.
+        server
+            .write_all(&[0xff, 0xff, 0xff, 0x00, 0x00, 0x00, 0x00, 0x00, 0x01])
+            .await
+            .unwrap();
+        assert!(matches!(
+            handler.receive_frames().await,
+            Err(ReceiveFrameError::FrameTooLarge {
+                length: 0xff_ffff,
+                max: 16384
+            })
+        ));
+
+        // A frame cut short by the peer.
+        server
+            .write_all(&[0x00, 0x00, 0x10, 0x00, 0x00, 0x00, 0x00, 0x00, 0x01, 0xaa])
+            .await
+            .unwrap();
+        drop(server);
+        assert!(matches!(
+            handler.receive_frames().await,
+            Err(ReceiveFrameError::IoError(_))
+        ));
+    }
+
+    #[test]
+    fn test_get_dvt_port_malformed() {
+        let (client_end, _server) = tokio::io::duplex(64);
+        let mut handler = XpcHandler::from_transport(Box::new(client_end));
+
+        assert!(handler.get_dvt_port(b"garbage".to_vec()).is_err());
+        assert!(handler
+            .get_dvt_port(b"com.apple.instruments.dtservicehubPort".to_vec())
+            .is_err());
+    }
+}
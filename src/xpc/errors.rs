@@ -84,6 +84,7 @@ impl Error for SendFrameError {
 #[derive(Debug)]
 pub enum ReceiveFrameError {
     IoError(std::io::Error),
+    FrameTooLarge { length: usize, max: usize },
 }
 
 impl From<std::io::Error> for ReceiveFrameError {
@@ -96,6 +97,13 @@ impl fmt::Display for ReceiveFrameError {
     fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
         match self {
             ReceiveFrameError::IoError(error) => write!(f, "Error receiving frame: {}", error),
+            ReceiveFrameError::FrameTooLarge { length, max } => {
+                write!(
+                    f,
+                    "Frame of {} bytes exceeds the {} byte limit",
+                    length, max
+                )
+            }
         }
     }
 }
@@ -104,6 +112,7 @@ impl Error for ReceiveFrameError {
     fn source(&self) -> Option<&(dyn Error + 'static)> {
         match self {
             ReceiveFrameError::IoError(error) => Some(error),
+            ReceiveFrameError::FrameTooLarge { .. } => None,
         }
     }
 }